@@ -1,7 +1,9 @@
 MEMORY
 {
-  FLASH :   ORIGIN = 0x08000000, LENGTH = 14K
+  FLASH :   ORIGIN = 0x08000000, LENGTH = 12K
+  STATE:    ORIGIN = 0x08003000, LENGTH = 2K
   SETTINGS: ORIGIN = 0x08003800, LENGTH = 2K
   APP:      ORIGIN = 0x08004000, LENGTH = 16K
-  RAM :     ORIGIN = 0x20000000, LENGTH = 8K
+  /* The last word of RAM is left alone at startup, for the handoff word */
+  RAM :     ORIGIN = 0x20000000, LENGTH = 8K - 4
 }
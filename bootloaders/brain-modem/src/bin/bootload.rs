@@ -1,11 +1,12 @@
 #![no_main]
 #![no_std]
 
-use core::sync::atomic::Ordering;
+use core::{convert::Infallible, sync::atomic::Ordering};
 
 use cortex_m::peripheral::SCB;
 use brain_bootloader::{self as _, GlobalRollingTimer}; // global logger + panicking-behavior + memory layout
 
+use groundhog::RollingTimer;
 use hal::block;
 use hal::hal::serial::{Read, Write};
 use hal::{
@@ -18,12 +19,16 @@ use hal::{
     time::U32Ext,
 };
 use squid_boot::{
+    handoff::{self, RamWord, Startup},
     icd::Parameters,
-    machine::{Flash, Machine},
+    machine::{Bootable, Flash, Machine, ReadPolicy},
+    transport::{Framer, Transport},
+    vectors::MemoryMap,
 };
 use stm32g0xx_hal as hal;
 
-//  0KiB - 14KiB: Bootloader
+//  0KiB - 12KiB: Bootloader
+// 12KiB - 14KiB: Bootloader state
 // 14KiB - 16KiB: Settings
 // 16KiB - 32KiB: Application
 const PARAMS: Parameters = Parameters {
@@ -32,8 +37,26 @@ const PARAMS: Parameters = Parameters {
     valid_flash_range: (0, 32 * 1024),
     valid_app_range: (16 * 1024, 32 * 1024),
     read_max: 2 * 1024,
+    alt_app_range: None,
 };
 
+// State that must survive a reset, but that the host can't write: it
+// isn't in an application slot or the settings page. Each word reads as
+// 0 until it is first written.
+const STATE_PAGE: u32 = 0x3000;
+// One double word, the smallest unit flash can be programmed in
+const STATE_LEN: usize = 8;
+const MIN_VERSION_WORD: usize = 0;
+const BOOT_ATTEMPTS_WORD: usize = 1;
+
+// The last word of RAM, outside of the RAM region in memory.x. The
+// application must also leave it out of its own RAM region.
+const HANDOFF_WORD: usize = 0x2000_1FFC;
+
+// How long to wait for a host before booting, if the
+// `boot_window_ms` setting is missing
+const DEFAULT_BOOT_WINDOW_MS: u32 = 1000;
+
 #[cortex_m_rt::entry]
 fn main() -> ! {
     // defmt::println!("Hello, world!");
@@ -51,8 +74,32 @@ struct StmFlash {
     hw: UnlockedFlash,
 }
 
+impl StmFlash {
+    fn state_word(&mut self, idx: usize) -> u32 {
+        let word = self.read_range(STATE_PAGE + (idx * 4) as u32, 4);
+        match u32::from_le_bytes([word[0], word[1], word[2], word[3]]) {
+            // Erased
+            0xFFFF_FFFF => 0,
+            n => n,
+        }
+    }
+
+    fn set_state_word(&mut self, idx: usize, val: u32) {
+        let mut state = [0u8; STATE_LEN];
+        state.copy_from_slice(self.read_range(STATE_PAGE, STATE_LEN as u32));
+        state[idx * 4..][..4].copy_from_slice(&val.to_le_bytes());
+        self.erase_range(STATE_PAGE, 2048);
+        self.flash_range(STATE_PAGE, &state);
+    }
+}
+
 impl Flash for StmFlash {
     const PARAMETERS: Parameters = PARAMS;
+    const SETTINGS_RANGE: Option<(u32, u32)> = Some((0x3800, 0x4000));
+    const MEMORY_MAP: Option<MemoryMap> = Some(MemoryMap {
+        flash_base: 0x0800_0000,
+        ram: (0x2000_0000, 0x2000_2000),
+    });
 
     fn flash_range(&mut self, start: u32, data: &[u8]) {
         self.hw.write(start as usize, data).ok();
@@ -90,12 +137,30 @@ impl Flash for StmFlash {
         }
     }
 
+    fn min_app_version(&mut self) -> u32 {
+        self.state_word(MIN_VERSION_WORD)
+    }
+
+    fn set_min_app_version(&mut self, version: u32) {
+        self.set_state_word(MIN_VERSION_WORD, version);
+    }
+
+    fn boot_attempts(&mut self) -> u32 {
+        self.state_word(BOOT_ATTEMPTS_WORD)
+    }
+
+    fn set_boot_attempts(&mut self, attempts: u32) {
+        self.set_state_word(BOOT_ATTEMPTS_WORD, attempts);
+    }
+
     fn boot(&mut self) -> ! {
+        // Whichever slot is active, `Machine::boot` has picked it already
+        let vectors = 0x0800_0000 + self.app_vectors();
         // o7
         unsafe {
             let scb = &*SCB::PTR;
-            scb.vtor.write(0x0800_4000);
-            cortex_m::asm::bootload(0x0800_4000usize as *const u32)
+            scb.vtor.write(vectors);
+            cortex_m::asm::bootload(vectors as *const u32)
         }
     }
 }
@@ -142,39 +207,78 @@ fn imain() -> Option<()> {
     };
 
     let mut machine = Machine::new(StmFlash { hw: flash });
+    machine.set_read_policy(ReadPolicy::AppAndSettings);
+
+    // Unless the application asked us to stay, give a host a little while
+    // to show up, then boot the application
+    let mut word = unsafe { RamWord::new(HANDOFF_WORD as *mut u32) };
+    let window = match handoff::check(&mut word) {
+        Startup::WaitForCommands => None,
+        Startup::AutoBoot => match machine.is_bootable() {
+            Bootable::Yes { .. } => {
+                Some(machine.boot_window_ms().unwrap_or(DEFAULT_BOOT_WINDOW_MS))
+            }
+            _ => None,
+        },
+    };
+    let timer = GlobalRollingTimer::new();
+    let start = timer.get_ticks();
 
-    'process: loop {
-        let mut idx = 0;
-        'byte: loop {
-            let cur = match buf.get_mut(idx) {
-                Some(c) => c,
-                None => {
-                    continue 'process;
+    let mut uart = Uart { tx, rx };
+    let mut framer = Framer::new(buf);
+    loop {
+        match framer.poll(&mut machine, &mut uart) {
+            Ok(true) => {
+                led_a.toggle().ok();
+                led_b.toggle().ok();
+            }
+            Ok(false) => {
+                if let Some(ms) = window {
+                    if !machine.received_request() && timer.millis_since(start) >= ms {
+                        machine.boot();
+                    }
                 }
-            };
+            }
+            Err(never) => match never {},
+        }
+    }
+}
 
-            match block!(rx.read()) {
+struct Uart<TX, RX> {
+    tx: TX,
+    rx: RX,
+}
+
+impl<TX: Write<u8>, RX: Read<u8>> Transport for Uart<TX, RX> {
+    type Error = Infallible;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match buf.first_mut() {
+            Some(cur) => match self.rx.read() {
                 Ok(byte) => {
                     *cur = byte;
-                    idx += 1;
-                    if byte == 0 {
-                        break 'byte;
-                    }
+                    Ok(1)
                 }
-                Err(_) => continue 'byte,
-            };
+                // Framing and overrun errors lose the byte, the frame's
+                // CRC will catch it
+                Err(_) => Ok(0),
+            },
+            None => Ok(0),
         }
-        let val = machine.process(buf);
-
-        led_a.toggle().ok();
-        led_b.toggle().ok();
+    }
 
-        if let Some(msg) = val {
-            msg.iter().for_each(|b| {
-                block!(tx.write(*b)).ok();
-            })
+    fn write(&mut self, data: &[u8]) -> Result<usize, Self::Error> {
+        match data.first() {
+            Some(byte) => match self.tx.write(*byte) {
+                Ok(()) => Ok(1),
+                Err(_) => Ok(0),
+            },
+            None => Ok(0),
         }
+    }
 
-        machine.check_after_send();
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        block!(self.tx.flush()).ok();
+        Ok(())
     }
 }
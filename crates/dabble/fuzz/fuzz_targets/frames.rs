@@ -0,0 +1,11 @@
+#![no_main]
+
+use dabble_fuzz::{machine, send};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut machine = machine();
+    for frame in data.split_inclusive(|b| *b == 0) {
+        send(&mut machine, frame);
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use dabble::CRC;
+use dabble_fuzz::{machine, send};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut machine = machine();
+    for body in data.split(|b| *b == 0) {
+        let mut raw = body.to_vec();
+        raw.extend_from_slice(&CRC.checksum(body).to_le_bytes());
+        let mut frame = cobs::encode_vec(&raw);
+        frame.push(0);
+        send(&mut machine, &frame);
+    }
+});
@@ -0,0 +1,60 @@
+//! Shared setup for the fuzz targets. Run them with e.g.
+//! `cargo +nightly fuzz run requests`.
+
+use dabble::{
+    icd::Parameters,
+    machine::Machine,
+    mock::{Layout, Op, RamFlash},
+};
+
+/// The STM32G031 layout, with two application slots
+pub struct Fuzz;
+
+impl Layout for Fuzz {
+    const PARAMETERS: Parameters = Parameters {
+        settings_max: (2 * 1024) - 4,
+        data_chunk_size: 2 * 1024,
+        valid_flash_range: (0, 64 * 1024),
+        valid_app_range: (16 * 1024, 40 * 1024),
+        read_max: 2 * 1024,
+        alt_app_range: Some((40 * 1024, 64 * 1024)),
+    };
+}
+
+/// The size of the device's frame buffer
+const BUF_LEN: usize = 3072;
+
+pub fn machine() -> Machine<RamFlash<Fuzz>> {
+    Machine::new(RamFlash::new())
+}
+
+/// Send one frame. Every frame must get a response that fits in the
+/// buffer, and flash may only be changed inside an application slot.
+pub fn send(machine: &mut Machine<RamFlash<Fuzz>>, frame: &[u8]) {
+    let mut buf = [0u8; BUF_LEN];
+    let resp = match buf.get_mut(..frame.len()) {
+        Some(used) => {
+            used.copy_from_slice(frame);
+            machine.process(&mut buf)
+        }
+        None => machine.nak_overfill(&mut buf),
+    };
+    assert!(resp.is_some());
+
+    let params = Fuzz::PARAMETERS;
+    let within = |(start, len): (u32, u32), (rstart, rend): (u32, u32)| {
+        start >= rstart && start + len <= rend
+    };
+    for op in machine.hardware().log() {
+        if let Op::Erase { start, len } | Op::Program { start, len } = *op {
+            let range = (start, len);
+            assert!(
+                within(range, params.valid_app_range)
+                    || params.alt_app_range.is_some_and(|alt| within(range, alt)),
+                "{:?}",
+                op
+            );
+        }
+    }
+    machine.hardware_mut().clear_log();
+}
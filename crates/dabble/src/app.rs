@@ -0,0 +1,182 @@
+//! Helpers for applications loaded by dabble
+//!
+//! These run in the application, not the bootloader. The application is
+//! responsible for reading and writing its own settings page: these helpers
+//! only ever prepare a new page in a buffer, for the application to write.
+
+use crate::icd::{
+    settings_from_raw, settings_to_slice, Setting, SettingVal, SettingsError, SettingsIter, Slot,
+};
+
+/// Settings with names starting with this describe the application image,
+/// and belong to the bootloader
+pub const RESERVED_PREFIX: &[u8] = b"app_";
+
+/// The settings page, as seen by the application
+#[derive(Clone)]
+pub struct Settings<'a> {
+    iter: SettingsIter<'a>,
+}
+
+impl<'a> Settings<'a> {
+    /// Check the settings page, usually a `&'static [u8]` pointing at flash
+    pub fn new(raw: &'a [u8]) -> Result<Self, SettingsError> {
+        Ok(Settings {
+            iter: settings_from_raw(raw)?,
+        })
+    }
+
+    /// All of the settings, in the order they are stored
+    pub fn iter(&self) -> SettingsIter<'a> {
+        self.iter.clone()
+    }
+
+    /// Look up a setting of any type
+    pub fn get(&self, name: &[u8]) -> Option<SettingVal<'a>> {
+        self.iter()
+            .find(|stg| stg.name_ascii == name)
+            .map(|stg| stg.val)
+    }
+
+    /// Look up a `SettingVal::U32`
+    pub fn get_u32(&self, name: &[u8]) -> Option<u32> {
+        match self.get(name)? {
+            SettingVal::U32(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Look up a `SettingVal::F32`
+    pub fn get_f32(&self, name: &[u8]) -> Option<f32> {
+        match self.get(name)? {
+            SettingVal::F32(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Look up a `SettingVal::ByteSlice`
+    pub fn get_bytes(&self, name: &[u8]) -> Option<&'a [u8]> {
+        match self.get(name)? {
+            SettingVal::ByteSlice(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Look up a `SettingVal::AsciiSlice`
+    pub fn get_ascii(&self, name: &[u8]) -> Option<&'a str> {
+        match self.get(name)? {
+            SettingVal::AsciiSlice(val) => core::str::from_utf8(val).ok(),
+            _ => None,
+        }
+    }
+
+    /// Prepare a new settings page with `name` set to `val`, replacing any
+    /// old value. Everything else is kept.
+    ///
+    /// Settings belonging to the bootloader can't be changed.
+    pub fn set<'b>(
+        &self,
+        name: &[u8],
+        val: SettingVal<'_>,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], SettingsError> {
+        if name.starts_with(RESERVED_PREFIX) {
+            return Err(SettingsError::Reserved);
+        }
+        let new = Setting {
+            name_ascii: name,
+            val,
+        };
+        let others = self.iter().filter(|stg| stg.name_ascii != name);
+        settings_to_slice(others.chain(core::iter::once(new)), buf)
+    }
+
+    /// Prepare a new settings page without `name`. Everything else is kept.
+    ///
+    /// Settings belonging to the bootloader can't be removed.
+    pub fn remove<'b>(&self, name: &[u8], buf: &'b mut [u8]) -> Result<&'b [u8], SettingsError> {
+        if name.starts_with(RESERVED_PREFIX) {
+            return Err(SettingsError::Reserved);
+        }
+        settings_to_slice(self.iter().filter(|stg| stg.name_ascii != name), buf)
+    }
+}
+
+/// Mark the running application as healthy, ending its trial.
+///
+/// A newly loaded application may be on trial: the bootloader only boots it
+/// a limited number of times before giving up on it. Call this once the
+/// application is sure it works.
+///
+/// Returns the new settings page, to be written in place of `settings_raw`,
+/// or `None` if the application wasn't on trial. `buf` must be at least as
+/// large as the settings page.
+pub fn confirm_boot<'a>(
+    settings_raw: &[u8],
+    buf: &'a mut [u8],
+) -> Result<Option<&'a [u8]>, SettingsError> {
+    let on_trial = |name: &[u8]| name == Slot::A.trial_setting() || name == Slot::B.trial_setting();
+    let settings = Settings::new(settings_raw)?;
+    if !settings.iter().any(|stg| on_trial(stg.name_ascii)) {
+        return Ok(None);
+    }
+    settings_to_slice(settings.iter().filter(|stg| !on_trial(stg.name_ascii)), buf).map(Some)
+}
+
+#[cfg(all(test, feature = "use-std"))]
+mod test {
+    use super::Settings;
+    use crate::icd::{settings_to_vec, Setting, SettingVal, SettingsError};
+
+    #[test]
+    fn lookups_and_updates() {
+        let raw = settings_to_vec(&[
+            Setting {
+                name_ascii: b"app_len",
+                val: SettingVal::U32(4096),
+            },
+            Setting {
+                name_ascii: b"serial",
+                val: SettingVal::U32(1234),
+            },
+            Setting {
+                name_ascii: b"gain",
+                val: SettingVal::F32(1.5),
+            },
+            Setting {
+                name_ascii: b"name",
+                val: SettingVal::AsciiSlice(b"squid"),
+            },
+        ]);
+        let settings = Settings::new(&raw).unwrap();
+        assert_eq!(settings.get_u32(b"serial"), Some(1234));
+        assert_eq!(settings.get_f32(b"gain"), Some(1.5));
+        assert_eq!(settings.get_ascii(b"name"), Some("squid"));
+        assert_eq!(settings.get_bytes(b"name"), None);
+        assert_eq!(settings.get_u32(b"nope"), None);
+
+        let mut buf = [0u8; 128];
+        let updated = settings
+            .set(b"serial", SettingVal::U32(5678), &mut buf)
+            .unwrap();
+        let updated = Settings::new(updated).unwrap();
+        assert_eq!(updated.get_u32(b"serial"), Some(5678));
+        assert_eq!(updated.get_u32(b"app_len"), Some(4096));
+        assert_eq!(updated.iter().count(), 4);
+
+        let mut buf2 = [0u8; 128];
+        let removed = Settings::new(updated.remove(b"gain", &mut buf2).unwrap()).unwrap();
+        assert_eq!(removed.get(b"gain"), None);
+        assert_eq!(removed.iter().count(), 3);
+
+        // Leave the bootloader's settings alone
+        assert_eq!(
+            settings.set(b"app_len", SettingVal::U32(0), &mut [0u8; 128]),
+            Err(SettingsError::Reserved)
+        );
+        assert_eq!(
+            Settings::new(&raw[..10]).err(),
+            Some(SettingsError::Corrupted)
+        );
+    }
+}
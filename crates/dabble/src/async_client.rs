@@ -0,0 +1,330 @@
+//! Async host-side client, on tokio
+//!
+//! [`AsyncClient`] does the same as the blocking
+//! [`Client`](crate::client::Client), over any `AsyncRead + AsyncWrite`
+//! stream: a `tokio_serial::SerialStream` (see [`AsyncClient::open_serial`]),
+//! a `TcpStream`, and so on. Each client is independent, so one process can
+//! drive many devices by spawning a task per client.
+//!
+//! Every operation can be cancelled by dropping its future, e.g. with
+//! `tokio::time::timeout` or `select!`. The client stays usable: a
+//! half-sent request is NAKed by the device and resent, and responses to
+//! cancelled requests are discarded. A cancelled load leaves the device
+//! bootloading, and the next load starts over.
+
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::watch,
+    time::timeout,
+};
+
+pub use crate::client::Progress;
+use crate::{
+    client::{ClientError, Core, Load, Next},
+    icd::{Request, Response, Setting, Slot},
+    machine::Bootable,
+};
+
+pub struct AsyncClient<T: AsyncRead + AsyncWrite + Unpin> {
+    port: T,
+    core: Core,
+    progress: watch::Sender<Progress>,
+}
+
+#[cfg(feature = "tokio-serial")]
+impl AsyncClient<tokio_serial::SerialStream> {
+    /// Open a serial port, e.g. `/dev/ttyACM0`
+    pub fn open_serial(path: &str, baud: u32) -> Result<Self, ClientError> {
+        use tokio_serial::SerialPortBuilderExt;
+
+        let port = tokio_serial::new(path, baud)
+            .open_native_async()
+            .map_err(std::io::Error::from)?;
+        Ok(Self::new(port))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncClient<T> {
+    pub fn new(port: T) -> Self {
+        Self {
+            port,
+            core: Core::new(),
+            progress: watch::channel(Progress::Idle).0,
+        }
+    }
+
+    /// See [`Client::set_allow_rollback`](crate::client::Client::set_allow_rollback)
+    pub fn set_allow_rollback(&mut self, allow: bool) {
+        self.core.allow_rollback = allow;
+    }
+
+    /// How long to wait for a response before giving up
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.core.timeout = timeout;
+    }
+
+    /// See [`Client::set_max_resends`](crate::client::Client::set_max_resends)
+    pub fn set_max_resends(&mut self, max_resends: usize) {
+        self.core.max_resends = max_resends;
+    }
+
+    /// Watch the progress of loads, e.g. from another task while this one
+    /// is flashing. Wrap it in a `tokio_stream::wrappers::WatchStream` for
+    /// a `Stream` of updates.
+    pub fn progress(&self) -> watch::Receiver<Progress> {
+        self.progress.subscribe()
+    }
+
+    /// Send a request, and wait for the response. Sequence numbers,
+    /// timeouts and NAKs are handled as in
+    /// [`Client::request`](crate::client::Client::request).
+    pub async fn request(&mut self, req: &Request<'_>) -> Result<Response<'_>, ClientError> {
+        let mut exchange = self.core.exchange(req);
+        self.send(exchange.frame()).await?;
+        loop {
+            let next = match timeout(self.core.timeout, self.receive_frame()).await {
+                Ok(Ok(())) => exchange.on_frame(self.core.frame())?,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => exchange.on_timeout()?,
+            };
+            match next {
+                Next::Wait => {}
+                Next::Resend => self.send(exchange.frame()).await?,
+                Next::Done => return self.core.response(),
+            }
+        }
+    }
+
+    async fn send(&mut self, frame: &[u8]) -> Result<(), ClientError> {
+        self.port.write_all(frame).await?;
+        self.port.flush().await?;
+        Ok(())
+    }
+
+    /// Receive exactly one frame. Cancelling this loses nothing: bytes are
+    /// kept until a whole frame is there.
+    async fn receive_frame(&mut self) -> Result<(), ClientError> {
+        while !self.core.next_frame() {
+            let mut buf = [0u8; 256];
+            match self.port.read(&mut buf).await? {
+                0 => return Err(ClientError::Io(std::io::ErrorKind::UnexpectedEof.into())),
+                n => self.core.received(&buf[..n]),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Higher level operations
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncClient<T> {
+    /// See [`Client::authenticate`](crate::client::Client::authenticate)
+    #[cfg(feature = "auth")]
+    pub async fn authenticate(&mut self, key: &[u8; 32]) -> Result<(), ClientError> {
+        let nonce = match self.request(&Request::GetChallenge).await? {
+            Response::Challenge { nonce } => nonce,
+            _ => return Err(ClientError::UnexpectedResponse),
+        };
+        let response = crate::auth::respond(key, &nonce);
+        match self.request(&Request::Authenticate { response }).await? {
+            Response::Authenticated => {
+                self.core.auth_key = Some(*key);
+                Ok(())
+            }
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Which slot the next image will be loaded into
+    pub async fn load_slot(&mut self) -> Result<Slot, ClientError> {
+        match self.request(&Request::GetSlots).await? {
+            Response::Slots { load, .. } => Ok(load),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Load an application image onto the device, as
+    /// [`Client::flash`](crate::client::Client::flash) does. Progress is
+    /// reported to [`AsyncClient::progress`].
+    pub async fn flash(
+        &mut self,
+        image: &[u8],
+        extra_settings: &[Setting<'_>],
+    ) -> Result<Bootable, ClientError> {
+        let load = Load::new(image, extra_settings, &self.core);
+        self.load(load).await
+    }
+
+    /// Load an application image onto a device that requires encrypted
+    /// images, otherwise the same as [`AsyncClient::flash`].
+    ///
+    /// `nonce` must never be reused with the same key.
+    #[cfg(feature = "encrypt")]
+    pub async fn flash_encrypted(
+        &mut self,
+        image: &[u8],
+        extra_settings: &[Setting<'_>],
+        key: &[u8; 32],
+        nonce: [u8; 12],
+    ) -> Result<Bootable, ClientError> {
+        let load = Load::new(image, extra_settings, &self.core);
+        self.load(load.encrypted(key, nonce)).await
+    }
+
+    async fn load(&mut self, mut load: Load<'_>) -> Result<Bootable, ClientError> {
+        loop {
+            self.progress.send_replace(load.progress());
+            let resp = self.request(&load.request()).await;
+            if let Some(status) = load.advance(resp)? {
+                self.progress.send_replace(Progress::Done(status.clone()));
+                return Ok(status);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AsyncClient, Progress};
+    use crate::{
+        client::{pad_image, ClientError},
+        icd::{Setting, SettingVal},
+        machine::{
+            test::{test_flash, G031},
+            Bootable, Machine,
+        },
+        mock::RamFlash,
+        transport::Framer,
+        CRC,
+    };
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// Serve `machine` over `port`, until the host hangs up
+    async fn device(mut port: DuplexStream, mut machine: Machine<RamFlash<G031>>) {
+        let mut framer = Framer::new(vec![0u8; 3072]);
+        let mut buf = [0u8; 256];
+        loop {
+            let used = match port.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(used) => used,
+            };
+            for byte in &buf[..used] {
+                if !framer.feed(*byte) {
+                    continue;
+                }
+                if let Some(msg) = framer.respond(&mut machine) {
+                    if port.write_all(msg).await.is_err() {
+                        return;
+                    }
+                }
+                machine.check_after_send();
+            }
+        }
+    }
+
+    /// A client talking to a freshly spawned device
+    async fn client() -> AsyncClient<DuplexStream> {
+        let (host, dev) = duplex(4096);
+        tokio::spawn(device(dev, Machine::new(test_flash::<G031>())));
+        #[cfg_attr(not(feature = "auth"), allow(unused_mut))]
+        let mut client = AsyncClient::new(host);
+        #[cfg(feature = "auth")]
+        client
+            .authenticate(&crate::machine::test::TEST_AUTH_KEY)
+            .await
+            .unwrap();
+        client
+    }
+
+    /// Flash `image`, signed and encrypted as the device expects
+    #[cfg_attr(not(feature = "encrypt"), allow(unused_variables))]
+    async fn flash(
+        client: &mut AsyncClient<DuplexStream>,
+        image: &[u8],
+        nonce: u8,
+    ) -> Result<Bootable, ClientError> {
+        #[cfg(feature = "ed25519")]
+        let sig = crate::sig::sign(
+            &crate::machine::test::TEST_SECRET_KEY,
+            &pad_image(image, 2048),
+            0,
+        );
+        let extra = [
+            #[cfg(feature = "ed25519")]
+            Setting {
+                name_ascii: crate::sig::SIG_SETTING,
+                val: SettingVal::ByteSlice(&sig),
+            },
+            Setting {
+                name_ascii: b"serial",
+                val: SettingVal::U32(1234),
+            },
+        ];
+
+        #[cfg(not(feature = "encrypt"))]
+        let status = client.flash(image, &extra).await;
+        #[cfg(feature = "encrypt")]
+        let status = client
+            .flash_encrypted(
+                image,
+                &extra,
+                &crate::machine::test::TEST_ENCRYPTION_KEY,
+                [nonce; 12],
+            )
+            .await;
+        status
+    }
+
+    fn bootable(image: &[u8]) -> Bootable {
+        let padded = pad_image(image, 2048);
+        Bootable::Yes {
+            crc32: CRC.checksum(&padded),
+            length: padded.len(),
+        }
+    }
+
+    #[tokio::test]
+    async fn many_devices() {
+        let tasks = (0..16u8)
+            .map(|i| {
+                tokio::spawn(async move {
+                    let mut client = client().await;
+                    let progress = client.progress();
+                    let image = vec![i; 5000];
+                    assert_eq!(
+                        flash(&mut client, &image, 8).await.unwrap(),
+                        bootable(&image)
+                    );
+                    assert_eq!(*progress.borrow(), Progress::Done(bootable(&image)));
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let mut client = client().await;
+        let mut progress = client.progress();
+        let image = [0x5A; 5000];
+
+        // Give up partway through loading
+        tokio::select! {
+            _ = flash(&mut client, &image, 8) => panic!("finished"),
+            _ = progress.wait_for(|p| matches!(p, Progress::Loading { .. })) => {}
+        }
+        assert!(matches!(
+            *progress.borrow(),
+            Progress::Loading { sent, total: 8192 } if sent < 8192
+        ));
+
+        // The client and device carry on as if nothing happened
+        assert_eq!(
+            flash(&mut client, &image, 9).await.unwrap(),
+            bootable(&image)
+        );
+    }
+}
@@ -0,0 +1,29 @@
+//! Host authentication
+//!
+//! Hosts prove they know the device's pre-shared key by answering a
+//! challenge: the device sends a fresh random nonce, and the host responds
+//! with an HMAC-SHA256 over that nonce.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Domain separation for challenge responses
+pub const AUTH_CONTEXT: &[u8] = b"dabble-auth";
+
+fn mac(key: &[u8; 32], nonce: &[u8; 16]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length, this can't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(AUTH_CONTEXT);
+    mac.update(nonce);
+    mac
+}
+
+/// Answer a challenge from the device
+pub fn respond(key: &[u8; 32], nonce: &[u8; 16]) -> [u8; 32] {
+    mac(key, nonce).finalize().into_bytes().into()
+}
+
+/// Check a host's answer to our challenge, in constant time
+pub fn verify(key: &[u8; 32], nonce: &[u8; 16], response: &[u8; 32]) -> bool {
+    mac(key, nonce).verify_slice(response).is_ok()
+}
@@ -0,0 +1,953 @@
+//! Host-side client
+//!
+//! This module contains a blocking client for talking to a device running
+//! a [`Machine`](crate::machine::Machine) over any byte stream, such as a
+//! serial port.
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    icd::{
+        decode_in_place, settings_from_raw, settings_to_vec, DataChunk, Parameters, Request,
+        RequestEnvelope, Response, ResponseEnvelope, ResponseError, Setting, SettingVal, Slot,
+        StartBootload,
+    },
+    machine::{Bootable, Error},
+    CRC,
+};
+
+/// What the client should do when the device NAKs a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetryPolicy {
+    /// The frame was damaged on the way to the device, and wasn't acted on.
+    /// A NAK carries no sequence number though, so it may be a late one for
+    /// an earlier request: only [idempotent](Request::is_idempotent)
+    /// requests are sent again.
+    Resend,
+    /// The frame arrived intact, but the device could not make sense of it.
+    /// Sending it again will not help.
+    Abort,
+}
+
+impl RetryPolicy {
+    /// Map a [`ResponseError::LineNak`] reason to a retry policy
+    pub fn for_line_nak(err: &Error) -> Self {
+        match err {
+            Error::Underfill | Error::Overfill | Error::Cobs | Error::Crc { .. } => {
+                RetryPolicy::Resend
+            }
+            Error::PostcardDecode | Error::LogicError => RetryPolicy::Abort,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    /// No complete response was received in time
+    Timeout,
+    /// The device could not decode the request
+    LineNak(Error),
+    /// The response from the device could not be decoded
+    BadResponse(Error),
+    /// The device decoded the request, and rejected it
+    Device(ResponseError),
+    /// The device sent a valid response, but not the one we asked for
+    UnexpectedResponse,
+    /// The image does not fit in the device's application range
+    ImageTooLarge {
+        max: u32,
+        actual: u32,
+    },
+}
+
+impl From<std::io::Error> for ClientError {
+    fn from(err: std::io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+pub struct Client<T: Read + Write> {
+    port: T,
+    core: Core,
+}
+
+impl<T: Read + Write> Client<T> {
+    pub fn new(port: T) -> Self {
+        Self {
+            port,
+            core: Core::new(),
+        }
+    }
+
+    /// Ask the device to accept images older than its minimum version.
+    /// Devices only allow this for authenticated hosts.
+    pub fn set_allow_rollback(&mut self, allow: bool) {
+        self.core.allow_rollback = allow;
+    }
+
+    /// How long to wait for a response before giving up
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.core.timeout = timeout;
+    }
+
+    /// How many times an [idempotent](Request::is_idempotent) request may
+    /// be re-sent after a timeout, a damaged response, or a
+    /// [`RetryPolicy::Resend`] NAK
+    pub fn set_max_resends(&mut self, max_resends: usize) {
+        self.core.max_resends = max_resends;
+    }
+
+    /// Send a request, and wait for the response.
+    ///
+    /// Each request is sent with a new sequence number. Responses carrying
+    /// any other sequence number (e.g. a late response to a request that
+    /// previously timed out) are discarded. NAKs are handled according to
+    /// [`RetryPolicy::for_line_nak`].
+    ///
+    /// Timeouts, damaged responses and NAKs are only retried for
+    /// [idempotent](Request::is_idempotent) requests. For anything else the
+    /// device may have acted on the request and lost the response, so the
+    /// error is returned, and it's up to the caller to find out what state
+    /// the device is in.
+    pub fn request(&mut self, req: &Request<'_>) -> Result<Response<'_>, ClientError> {
+        let mut exchange = self.core.exchange(req);
+        self.send(exchange.frame())?;
+        loop {
+            let next = match self.receive_frame() {
+                Ok(()) => exchange.on_frame(self.core.frame())?,
+                Err(ClientError::Timeout) => exchange.on_timeout()?,
+                Err(e) => return Err(e),
+            };
+            match next {
+                Next::Wait => {}
+                Next::Resend => self.send(exchange.frame())?,
+                Next::Done => return self.core.response(),
+            }
+        }
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), ClientError> {
+        self.port.write_all(frame)?;
+        self.port.flush()?;
+        Ok(())
+    }
+
+    /// Receive exactly one frame
+    fn receive_frame(&mut self) -> Result<(), ClientError> {
+        let deadline = Instant::now() + self.core.timeout;
+        while !self.core.next_frame() {
+            if Instant::now() >= deadline {
+                return Err(ClientError::Timeout);
+            }
+
+            let mut buf = [0u8; 256];
+            match self.port.read(&mut buf) {
+                Ok(0) => return Err(ClientError::Io(ErrorKind::UnexpectedEof.into())),
+                Ok(n) => self.core.received(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Higher level operations
+impl<T: Read + Write> Client<T> {
+    /// Authenticate with a device that requires it, using the pre-shared key.
+    ///
+    /// The session lasts until a load finishes, or the device is told to
+    /// boot. Loads keep the key to log in again if they have to abort an
+    /// unfinished load first.
+    #[cfg(feature = "auth")]
+    pub fn authenticate(&mut self, key: &[u8; 32]) -> Result<(), ClientError> {
+        let nonce = match self.request(&Request::GetChallenge)? {
+            Response::Challenge { nonce } => nonce,
+            _ => return Err(ClientError::UnexpectedResponse),
+        };
+        let response = crate::auth::respond(key, &nonce);
+        match self.request(&Request::Authenticate { response })? {
+            Response::Authenticated => {
+                self.core.auth_key = Some(*key);
+                Ok(())
+            }
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Which slot the next image will be loaded into. Settings describing
+    /// the image, like its signature, need to use that slot's names.
+    pub fn load_slot(&mut self) -> Result<Slot, ClientError> {
+        match self.request(&Request::GetSlots)? {
+            Response::Slots { load, .. } => Ok(load),
+            _ => Err(ClientError::UnexpectedResponse),
+        }
+    }
+
+    /// Load an application image onto the device.
+    ///
+    /// The image is padded with [`pad_image`], loaded into the inactive slot,
+    /// and then described in the settings page (`app_len` and `app_crc`, or
+    /// their slot B equivalents), along with any `extra_settings`. Other
+    /// settings already on the device are kept. A load left unfinished by an
+    /// earlier client is aborted.
+    ///
+    /// The image's version is taken from the load slot's version setting
+    /// (see [`Slot::version_setting`]) in `extra_settings`, if there is one,
+    /// and is 0 otherwise. On devices that require signed images, the
+    /// signature must cover that version.
+    ///
+    /// Returns whether the device now considers the application bootable.
+    pub fn flash(
+        &mut self,
+        image: &[u8],
+        extra_settings: &[Setting<'_>],
+    ) -> Result<Bootable, ClientError> {
+        let load = Load::new(image, extra_settings, &self.core);
+        self.load(load)
+    }
+
+    /// Load an application image onto a device that requires encrypted
+    /// images, otherwise the same as [`Client::flash`].
+    ///
+    /// `nonce` must never be reused with the same key.
+    #[cfg(feature = "encrypt")]
+    pub fn flash_encrypted(
+        &mut self,
+        image: &[u8],
+        extra_settings: &[Setting<'_>],
+        key: &[u8; 32],
+        nonce: [u8; 12],
+    ) -> Result<Bootable, ClientError> {
+        let load = Load::new(image, extra_settings, &self.core);
+        self.load(load.encrypted(key, nonce))
+    }
+
+    fn load(&mut self, mut load: Load<'_>) -> Result<Bootable, ClientError> {
+        loop {
+            let resp = self.request(&load.request());
+            if let Some(status) = load.advance(resp)? {
+                return Ok(status);
+            }
+        }
+    }
+}
+
+/// How far along a load is, as reported by
+/// [`AsyncClient::progress`](crate::async_client::AsyncClient::progress)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Progress {
+    /// Nothing loaded yet
+    Idle,
+    /// Checking the device's parameters and settings
+    Starting,
+    /// `sent` of `total` bytes of the padded image have been accepted
+    Loading { sent: u32, total: u32 },
+    /// The image is loaded, and is being described in the settings
+    WritingSettings,
+    /// The last load finished, with this result
+    Done(Bootable),
+}
+
+/// The parts of a client that don't do any I/O: settings, sequence numbers,
+/// and splitting what the device sends into frames. [`Client`] and
+/// [`AsyncClient`](crate::async_client::AsyncClient) only add the reads and
+/// writes.
+pub(crate) struct Core {
+    rx: Vec<u8>,
+    frame: Vec<u8>,
+    next_seq: u32,
+    pub(crate) timeout: Duration,
+    pub(crate) max_resends: usize,
+    pub(crate) allow_rollback: bool,
+    /// The key from the last successful authentication
+    #[cfg(feature = "auth")]
+    pub(crate) auth_key: Option<[u8; 32]>,
+}
+
+impl Core {
+    pub(crate) fn new() -> Self {
+        Core {
+            rx: Vec::new(),
+            frame: Vec::new(),
+            next_seq: 0,
+            timeout: Duration::from_secs(3),
+            max_resends: 3,
+            allow_rollback: false,
+            #[cfg(feature = "auth")]
+            auth_key: None,
+        }
+    }
+
+    /// Start sending `req`, with a new sequence number
+    pub(crate) fn exchange(&mut self, req: &Request<'_>) -> Exchange {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        Exchange {
+            seq,
+            frame: RequestEnvelope {
+                seq,
+                req: req.clone(),
+            }
+            .encode_to_vec(),
+            resends: 0,
+            max_resends: self.max_resends,
+            idempotent: req.is_idempotent(),
+        }
+    }
+
+    /// Add bytes read from the device
+    pub(crate) fn received(&mut self, data: &[u8]) {
+        self.rx.extend_from_slice(data);
+    }
+
+    /// Take the next complete frame out of what has been received, if
+    /// there is one, making it the current [`Core::frame`]
+    pub(crate) fn next_frame(&mut self) -> bool {
+        match self.rx.iter().position(|b| *b == 0) {
+            Some(pos) => {
+                // Keep anything after the terminator for next time
+                let remain = self.rx.split_off(pos + 1);
+                self.frame = core::mem::replace(&mut self.rx, remain);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Decode the current frame, once an [`Exchange`] found it was our
+    /// response
+    pub(crate) fn response(&mut self) -> Result<Response<'_>, ClientError> {
+        match decode_in_place::<ResponseEnvelope<'_>>(&mut self.frame) {
+            Ok(ResponseEnvelope { resp: Ok(resp), .. }) => Ok(resp),
+            Ok(ResponseEnvelope { resp: Err(err), .. }) => Err(ClientError::Device(err)),
+            Err(e) => Err(ClientError::BadResponse(e)),
+        }
+    }
+}
+
+/// What to do next while waiting for a response
+#[derive(Debug, PartialEq)]
+pub(crate) enum Next {
+    /// Keep waiting
+    Wait,
+    /// Send [`Exchange::frame`] again
+    Resend,
+    /// The response has arrived, see [`Core::response`]
+    Done,
+}
+
+/// One request, and the wait for its response
+pub(crate) struct Exchange {
+    seq: u32,
+    frame: Vec<u8>,
+    resends: usize,
+    max_resends: usize,
+    idempotent: bool,
+}
+
+impl Exchange {
+    /// The encoded request, to send
+    pub(crate) fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// No response arrived in time. Either the request or the response was
+    /// lost, so only idempotent requests are resent. We'll ignore any late
+    /// response to an earlier attempt.
+    pub(crate) fn on_timeout(&mut self) -> Result<Next, ClientError> {
+        if !self.idempotent {
+            return Err(ClientError::Timeout);
+        }
+        self.resend().ok_or(ClientError::Timeout)
+    }
+
+    /// `frame` arrived from the device
+    pub(crate) fn on_frame(&mut self, frame: &[u8]) -> Result<Next, ClientError> {
+        // Peek at a copy of the frame, it's only decoded for keeps once we
+        // know it's the response
+        let mut peek = frame.to_vec();
+        match decode_in_place::<ResponseEnvelope<'_>>(&mut peek) {
+            Ok(ResponseEnvelope { seq: Some(n), .. }) if n != self.seq => Ok(Next::Wait),
+            Ok(ResponseEnvelope {
+                resp: Err(ResponseError::LineNak(e)),
+                ..
+            }) => match RetryPolicy::for_line_nak(&e) {
+                RetryPolicy::Resend if self.idempotent => {
+                    self.resend().ok_or(ClientError::LineNak(e))
+                }
+                _ => Err(ClientError::LineNak(e)),
+            },
+            Ok(_) => Ok(Next::Done),
+            // Damaged on the way back, so this may well have been our
+            // response: treat it like one that never arrived
+            Err(e) if self.idempotent => self.resend().ok_or(ClientError::BadResponse(e)),
+            Err(e) => Err(ClientError::BadResponse(e)),
+        }
+    }
+
+    fn resend(&mut self) -> Option<Next> {
+        if self.resends >= self.max_resends {
+            return None;
+        }
+        self.resends += 1;
+        Some(Next::Resend)
+    }
+}
+
+/// Where a [`Load`] is up to, each step waiting on the response to one
+/// request
+enum Step {
+    Parameters,
+    Slots(Parameters),
+    Settings,
+    /// `retried` once an unfinished load has been aborted
+    Start {
+        retried: bool,
+    },
+    Abort,
+    /// Aborting ended the session, so log in again
+    #[cfg(feature = "auth")]
+    Challenge,
+    #[cfg(feature = "auth")]
+    Authenticate {
+        response: [u8; 32],
+    },
+    /// The chunk at this offset into the image
+    Chunk(u32),
+    WriteSettings,
+    Complete,
+}
+
+/// The requests for loading an image, and what to make of the responses
+pub(crate) struct Load<'a> {
+    image: &'a [u8],
+    extra_settings: &'a [Setting<'a>],
+    allow_rollback: bool,
+    #[cfg(feature = "auth")]
+    auth_key: Option<[u8; 32]>,
+    nonce: Option<[u8; 12]>,
+    #[cfg(feature = "encrypt")]
+    key: Option<&'a [u8; 32]>,
+    step: Step,
+
+    // Filled in once the device's parameters are known
+    slot: Slot,
+    app_start: u32,
+    chunk_size: u32,
+    /// The padded image, which CRCs are always over
+    padded: Vec<u8>,
+    crc32: u32,
+    /// The padded image as sent, which may be encrypted
+    wire: Vec<u8>,
+    settings: Vec<u8>,
+}
+
+impl<'a> Load<'a> {
+    pub(crate) fn new(image: &'a [u8], extra_settings: &'a [Setting<'a>], core: &Core) -> Self {
+        Load {
+            image,
+            extra_settings,
+            allow_rollback: core.allow_rollback,
+            #[cfg(feature = "auth")]
+            auth_key: core.auth_key,
+            nonce: None,
+            #[cfg(feature = "encrypt")]
+            key: None,
+            step: Step::Parameters,
+            slot: Slot::A,
+            app_start: 0,
+            chunk_size: 0,
+            padded: Vec::new(),
+            crc32: 0,
+            wire: Vec::new(),
+            settings: Vec::new(),
+        }
+    }
+
+    /// Send the image encrypted with `key`
+    #[cfg(feature = "encrypt")]
+    pub(crate) fn encrypted(self, key: &'a [u8; 32], nonce: [u8; 12]) -> Self {
+        Load {
+            key: Some(key),
+            nonce: Some(nonce),
+            ..self
+        }
+    }
+
+    /// The request to send next
+    pub(crate) fn request(&self) -> Request<'_> {
+        match self.step {
+            Step::Parameters => Request::GetParameters,
+            Step::Slots(_) => Request::GetSlots,
+            Step::Settings => Request::GetSettings,
+            Step::Start { .. } => Request::StartBootload(StartBootload {
+                start_addr: self.app_start,
+                length: self.padded.len() as u32,
+                crc32: self.crc32,
+                nonce: self.nonce,
+                app_version: app_version(self.extra_settings, self.slot),
+                allow_rollback: self.allow_rollback,
+            }),
+            Step::Abort => Request::AbortBootload,
+            #[cfg(feature = "auth")]
+            Step::Challenge => Request::GetChallenge,
+            #[cfg(feature = "auth")]
+            Step::Authenticate { response } => Request::Authenticate { response },
+            Step::Chunk(offset) => {
+                let start = offset as usize;
+                let end = (start + self.chunk_size as usize).min(self.padded.len());
+                Request::DataChunk(DataChunk {
+                    data_addr: self.app_start + offset,
+                    sub_crc32: CRC.checksum(&self.padded[start..end]),
+                    data: &self.wire[start..end],
+                })
+            }
+            Step::WriteSettings => Request::WriteSettings {
+                data: &self.settings,
+            },
+            Step::Complete => Request::CompleteBootload { boot: None },
+        }
+    }
+
+    /// Take in the response to [`Load::request`]. Returns the result of
+    /// the load once it's done.
+    pub(crate) fn advance(
+        &mut self,
+        resp: Result<Response<'_>, ClientError>,
+    ) -> Result<Option<Bootable>, ClientError> {
+        self.step = match (&self.step, resp) {
+            // Left over from a load that was never finished. Start over.
+            (
+                Step::Start { retried: false },
+                Err(ClientError::Device(ResponseError::BootloadInProgress)),
+            ) => Step::Abort,
+            (_, Err(e)) => return Err(e),
+            (Step::Parameters, Ok(Response::Parameters(params))) => Step::Slots(params),
+            (Step::Slots(params), Ok(Response::Slots { load, .. })) => {
+                let params = *params;
+                self.prepare(&params, load)?;
+                Step::Settings
+            }
+            (Step::Settings, Ok(Response::Settings { data })) => {
+                let length = self.padded.len() as u32;
+                self.settings =
+                    merge_settings(data, self.slot, length, self.crc32, self.extra_settings);
+                Step::Start { retried: false }
+            }
+            (Step::Start { .. }, Ok(Response::BootloadStarted)) => Step::Chunk(0),
+            (Step::Abort, Ok(Response::BootloadAborted)) => self.after_abort(),
+            #[cfg(feature = "auth")]
+            (Step::Challenge, Ok(Response::Challenge { nonce })) => match &self.auth_key {
+                Some(key) => Step::Authenticate {
+                    response: crate::auth::respond(key, &nonce),
+                },
+                None => return Err(ClientError::UnexpectedResponse),
+            },
+            #[cfg(feature = "auth")]
+            (Step::Authenticate { .. }, Ok(Response::Authenticated)) => {
+                Step::Start { retried: true }
+            }
+            (Step::Chunk(offset), Ok(Response::ChunkAccepted { .. })) => {
+                let next = offset + self.chunk_size;
+                if (next as usize) < self.padded.len() {
+                    Step::Chunk(next)
+                } else {
+                    Step::WriteSettings
+                }
+            }
+            (Step::WriteSettings, Ok(Response::SettingsAccepted { .. })) => Step::Complete,
+            (Step::Complete, Ok(Response::ConfirmComplete { boot_status, .. })) => {
+                return Ok(Some(boot_status))
+            }
+            _ => return Err(ClientError::UnexpectedResponse),
+        };
+        Ok(None)
+    }
+
+    /// How far along the load is
+    #[cfg(feature = "tokio")]
+    pub(crate) fn progress(&self) -> Progress {
+        match self.step {
+            Step::Chunk(offset) => Progress::Loading {
+                sent: offset,
+                total: self.padded.len() as u32,
+            },
+            Step::WriteSettings | Step::Complete => Progress::WritingSettings,
+            _ => Progress::Starting,
+        }
+    }
+
+    /// Where to go once an unfinished load has been aborted, which also
+    /// ended any session
+    fn after_abort(&self) -> Step {
+        #[cfg(feature = "auth")]
+        if self.auth_key.is_some() {
+            return Step::Challenge;
+        }
+        Step::Start { retried: true }
+    }
+
+    /// Pad and encrypt the image for loading into `slot`
+    fn prepare(&mut self, params: &Parameters, slot: Slot) -> Result<(), ClientError> {
+        let (app_start, app_end) = match slot.range(params) {
+            Some(range) => range,
+            None => return Err(ClientError::UnexpectedResponse),
+        };
+        let padded = pad_image(self.image, params.data_chunk_size);
+        if padded.len() as u32 > (app_end - app_start) {
+            return Err(ClientError::ImageTooLarge {
+                max: app_end - app_start,
+                actual: padded.len() as u32,
+            });
+        }
+
+        #[allow(unused_mut)]
+        let mut wire = padded.clone();
+        #[cfg(feature = "encrypt")]
+        if let (Some(key), Some(nonce)) = (self.key, &self.nonce) {
+            crate::crypt::apply_keystream(key, nonce, 0, &mut wire);
+        }
+
+        self.slot = slot;
+        self.app_start = app_start;
+        self.chunk_size = params.data_chunk_size;
+        self.crc32 = CRC.checksum(&padded);
+        self.padded = padded;
+        self.wire = wire;
+        Ok(())
+    }
+}
+
+/// The version from `slot`'s version setting in `extra_settings`, or 0
+fn app_version(extra_settings: &[Setting<'_>], slot: Slot) -> u32 {
+    extra_settings
+        .iter()
+        .find_map(|stg| match stg {
+            Setting {
+                name_ascii,
+                val: SettingVal::U32(version),
+            } if *name_ascii == slot.version_setting() => Some(*version),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+/// The settings page after loading an image into `slot`: `existing`, with
+/// anything describing the old image replaced
+fn merge_settings(
+    existing: &[u8],
+    slot: Slot,
+    length: u32,
+    crc32: u32,
+    extra_settings: &[Setting<'_>],
+) -> Vec<u8> {
+    let ours = [
+        Setting {
+            name_ascii: slot.len_setting(),
+            val: SettingVal::U32(length),
+        },
+        Setting {
+            name_ascii: slot.crc_setting(),
+            val: SettingVal::U32(crc32),
+        },
+    ];
+    let replaced = |stg: &Setting<'_>| {
+        // A trial only applies to the image it was started for
+        stg.name_ascii == slot.trial_setting()
+            || ours
+                .iter()
+                .chain(extra_settings)
+                .any(|new| new.name_ascii == stg.name_ascii)
+    };
+    let mut settings: Vec<Setting<'_>> = match settings_from_raw(existing) {
+        Ok(iter) => iter.filter(|stg| !replaced(stg)).collect(),
+        Err(_) => Vec::new(),
+    };
+    settings.extend(ours);
+    settings.extend(extra_settings.iter().cloned());
+    settings_to_vec(&settings)
+}
+
+/// Pad an application image the way the device expects to receive (and
+/// hash) it.
+///
+/// The device only accepts images that are a power of two in length, and
+/// at least one chunk long. Padding is done with `0xFF`, matching erased
+/// flash.
+pub fn pad_image(image: &[u8], chunk_size: u32) -> Vec<u8> {
+    let len = image.len().next_power_of_two().max(chunk_size as usize);
+    let mut padded = image.to_vec();
+    padded.resize(len, 0xFF);
+    padded
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{pad_image, Client, ClientError, RetryPolicy};
+    use crate::{
+        icd::{
+            settings_from_raw, settings_to_vec, Request, Response, ResponseEnvelope, ResponseError,
+            Setting, SettingVal, StartBootload, Status,
+        },
+        machine::{
+            test::{chunk, test_flash, G031, TEST_NONCE},
+            Bootable, Error, Machine,
+        },
+        mock::RamFlash,
+        transport::Loopback,
+        CRC,
+    };
+    use std::time::Duration;
+
+    type TestClient = Client<Loopback<RamFlash<G031>>>;
+
+    fn client(corrupt: usize) -> TestClient {
+        let machine = Machine::new(test_flash::<G031>());
+        let mut client = Client::new(Loopback::new(machine, 3072));
+        authenticate(&mut client);
+        client.port.corrupt_requests(corrupt);
+        client
+    }
+
+    #[cfg(feature = "auth")]
+    fn authenticate(client: &mut TestClient) {
+        client
+            .authenticate(&crate::machine::test::TEST_AUTH_KEY)
+            .unwrap();
+    }
+
+    #[cfg(not(feature = "auth"))]
+    fn authenticate(_client: &mut TestClient) {}
+
+    #[test]
+    fn resends_after_corruption() {
+        let mut client = client(2);
+        assert_eq!(
+            client.request(&Request::Ping(42)).unwrap(),
+            Response::Pong(42)
+        );
+    }
+
+    #[test]
+    fn gives_up_after_max_resends() {
+        let mut client = client(10);
+        client.set_max_resends(2);
+        let res = client.request(&Request::Ping(42));
+        assert!(matches!(res, Err(ClientError::LineNak(_))));
+    }
+
+    #[test]
+    fn commands_are_not_resent_after_a_nak() {
+        let mut client = client(1);
+        let res = client.request(&Request::AbortBootload);
+        assert!(matches!(res, Err(ClientError::LineNak(_))));
+
+        // Had it been resent, the device would have said there was nothing
+        // to abort
+        assert_eq!(
+            client.request(&Request::Ping(42)).unwrap(),
+            Response::Pong(42)
+        );
+    }
+
+    #[test]
+    fn discards_stale_responses() {
+        let mut client = client(0);
+
+        // A late response to some earlier request is already waiting
+        let stale = ResponseEnvelope {
+            seq: Some(1234),
+            resp: Ok(Response::Pong(1234)),
+        };
+        client.port.inject(&stale.encode_to_vec());
+
+        assert_eq!(
+            client.request(&Request::Ping(42)).unwrap(),
+            Response::Pong(42)
+        );
+        assert_eq!(
+            client.request(&Request::Ping(43)).unwrap(),
+            Response::Pong(43)
+        );
+    }
+
+    #[test]
+    fn resends_after_timeout() {
+        let mut client = client(0);
+        client.set_timeout(Duration::from_millis(10));
+
+        let chunk = chunk(16 * 1024, 16);
+        let start = Request::StartBootload(StartBootload {
+            start_addr: 16 * 1024,
+            length: 4 * 1024,
+            crc32: 0,
+            nonce: TEST_NONCE,
+            app_version: 0,
+            allow_rollback: false,
+        });
+        assert_eq!(client.request(&start).unwrap(), Response::BootloadStarted);
+
+        // The first ChunkAccepted is lost, the device accepts the resent chunk again
+        client.port.drop_responses(1);
+        assert_eq!(
+            client.request(&chunk.req()).unwrap(),
+            Response::ChunkAccepted {
+                data_addr: 16 * 1024,
+                data_len: 2048,
+                crc32: CRC.checksum(&[16; 2048]),
+            }
+        );
+    }
+
+    #[test]
+    fn resends_after_a_damaged_response() {
+        let mut client = client(0);
+        let start = Request::StartBootload(StartBootload {
+            start_addr: 16 * 1024,
+            length: 4 * 1024,
+            crc32: 0,
+            nonce: TEST_NONCE,
+            app_version: 0,
+            allow_rollback: false,
+        });
+        assert_eq!(client.request(&start).unwrap(), Response::BootloadStarted);
+
+        let chunk = chunk(16 * 1024, 16);
+        client.port.corrupt_responses(1);
+        assert_eq!(
+            client.request(&chunk.req()).unwrap(),
+            Response::ChunkAccepted {
+                data_addr: 16 * 1024,
+                data_len: 2048,
+                crc32: CRC.checksum(&[16; 2048]),
+            }
+        );
+
+        // Ending the load isn't resent
+        client.port.corrupt_responses(1);
+        let res = client.request(&Request::AbortBootload);
+        assert!(matches!(res, Err(ClientError::BadResponse(_))));
+        assert!(matches!(
+            client.request(&Request::GetStatus).unwrap(),
+            Response::Status(Status::Idle)
+        ));
+    }
+
+    #[test]
+    fn commands_are_not_resent_after_timeout() {
+        let mut client = client(0);
+        client.set_timeout(Duration::from_millis(10));
+
+        let start = Request::StartBootload(StartBootload {
+            start_addr: 16 * 1024,
+            length: 4 * 1024,
+            crc32: 0,
+            nonce: TEST_NONCE,
+            app_version: 0,
+            allow_rollback: false,
+        });
+        client.port.drop_responses(1);
+        assert!(matches!(client.request(&start), Err(ClientError::Timeout)));
+
+        // The device did start, and wasn't told to start again
+        assert!(matches!(
+            client.request(&Request::GetStatus).unwrap(),
+            Response::Status(Status::Started { .. })
+        ));
+    }
+
+    #[test]
+    fn device_errors_are_not_retried() {
+        let mut client = client(0);
+        let res = client.request(&Request::AbortBootload);
+        assert!(matches!(
+            res,
+            Err(ClientError::Device(ResponseError::NoBootloadActive))
+        ));
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(
+            RetryPolicy::for_line_nak(&Error::Overfill),
+            RetryPolicy::Resend
+        );
+        assert_eq!(
+            RetryPolicy::for_line_nak(&Error::PostcardDecode),
+            RetryPolicy::Abort
+        );
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(pad_image(&[1; 100], 2048).len(), 2048);
+        assert_eq!(pad_image(&[1; 5000], 2048).len(), 8192);
+        assert_eq!(pad_image(&[1; 8192], 2048).len(), 8192);
+        assert_eq!(pad_image(&[1; 5000], 2048)[5000..], [0xFF; 8192 - 5000]);
+    }
+
+    #[test]
+    fn flash_an_image() {
+        let mut client = client(0);
+
+        // Something the application put there earlier
+        let serial = Setting {
+            name_ascii: b"serial",
+            val: SettingVal::U32(1234),
+        };
+        let existing = settings_to_vec(core::slice::from_ref(&serial));
+        client
+            .request(&Request::WriteSettings { data: &existing })
+            .unwrap();
+
+        let image = [0x5A; 5000];
+        let padded = pad_image(&image, 2048);
+        #[cfg(feature = "ed25519")]
+        let sig = crate::sig::sign(&crate::machine::test::TEST_SECRET_KEY, &padded, 0);
+        let extra = [
+            #[cfg(feature = "ed25519")]
+            Setting {
+                name_ascii: crate::sig::SIG_SETTING,
+                val: SettingVal::ByteSlice(&sig),
+            },
+        ];
+
+        #[cfg(not(feature = "encrypt"))]
+        let status = client.flash(&image, &extra).unwrap();
+        #[cfg(feature = "encrypt")]
+        let status = client
+            .flash_encrypted(
+                &image,
+                &extra,
+                &crate::machine::test::TEST_ENCRYPTION_KEY,
+                [0x08; 12],
+            )
+            .unwrap();
+        assert_eq!(
+            status,
+            Bootable::Yes {
+                crc32: CRC.checksum(&padded),
+                length: 8192,
+            }
+        );
+
+        // The session ended with the load
+        authenticate(&mut client);
+        let settings = match client.request(&Request::GetSettings).unwrap() {
+            Response::Settings { data } => data.to_vec(),
+            _ => panic!(),
+        };
+        let settings = settings_from_raw(&settings).unwrap().collect::<Vec<_>>();
+        assert!(settings.contains(&serial));
+    }
+}
@@ -0,0 +1,20 @@
+//! Application image encryption
+//!
+//! Images are encrypted with ChaCha20, using a per-device key and a fresh
+//! nonce for each load, sent with `StartBootload`. The keystream position is
+//! the byte offset into the image, so each chunk can be decrypted on its own.
+//!
+//! Only the data on the wire is encrypted: CRCs, `ChunkAccepted`
+//! acknowledgements, and the image in flash are all plaintext.
+
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    ChaCha20,
+};
+
+/// Encrypt or decrypt `data`, which starts `offset` bytes into the image
+pub fn apply_keystream(key: &[u8; 32], nonce: &[u8; 12], offset: u32, data: &mut [u8]) {
+    let mut cipher = ChaCha20::new(key.into(), nonce.into());
+    cipher.seek(offset);
+    cipher.apply_keystream(data);
+}
@@ -0,0 +1,98 @@
+//! Handing off between the application and the bootloader
+//!
+//! An application asks to stay in the bootloader after the next reset by
+//! leaving a magic word somewhere that survives a reset: a word of RAM that
+//! neither the application nor the bootloader initializes, or a backup
+//! register. Both sides must agree on where that is.
+
+/// Left by the application to ask the bootloader to wait for commands
+pub const STAY_IN_BOOTLOADER: u32 = 0xB007_10AD;
+
+/// Somewhere a word can be left across a reset
+pub trait HandoffWord {
+    fn read(&mut self) -> u32;
+    fn write(&mut self, val: u32);
+}
+
+/// A [`HandoffWord`] in RAM
+pub struct RamWord {
+    ptr: *mut u32,
+}
+
+impl RamWord {
+    /// # Safety
+    ///
+    /// `ptr` must be valid and aligned, and must not be used for anything
+    /// else by either the application or the bootloader. It must also not
+    /// be zeroed or initialized at startup, e.g. by keeping it out of the
+    /// RAM region in both `memory.x` files.
+    pub const unsafe fn new(ptr: *mut u32) -> Self {
+        RamWord { ptr }
+    }
+}
+
+impl HandoffWord for RamWord {
+    fn read(&mut self) -> u32 {
+        unsafe { self.ptr.read_volatile() }
+    }
+
+    fn write(&mut self, val: u32) {
+        unsafe { self.ptr.write_volatile(val) }
+    }
+}
+
+/// What the bootloader should do after a reset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Startup {
+    /// The application asked to stay in the bootloader
+    WaitForCommands,
+    /// Boot the application, if it is bootable
+    AutoBoot,
+}
+
+/// Bootloader side: decide what to do after a reset.
+///
+/// The word is cleared, so a request only applies to one reset.
+pub fn check(word: &mut impl HandoffWord) -> Startup {
+    let requested = word.read() == STAY_IN_BOOTLOADER;
+    word.write(0);
+    if requested {
+        Startup::WaitForCommands
+    } else {
+        Startup::AutoBoot
+    }
+}
+
+/// Application side: reset into the bootloader, and stay there
+///
+/// `reset` is usually `cortex_m::peripheral::SCB::sys_reset`.
+pub fn enter_bootloader(word: &mut impl HandoffWord, reset: fn() -> !) -> ! {
+    word.write(STAY_IN_BOOTLOADER);
+    reset()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, HandoffWord, Startup, STAY_IN_BOOTLOADER};
+
+    impl HandoffWord for u32 {
+        fn read(&mut self) -> u32 {
+            *self
+        }
+
+        fn write(&mut self, val: u32) {
+            *self = val;
+        }
+    }
+
+    #[test]
+    fn only_once() {
+        // Whatever was in RAM at power on
+        let mut word = 0x1234_5678u32;
+        assert_eq!(check(&mut word), Startup::AutoBoot);
+
+        word = STAY_IN_BOOTLOADER;
+        assert_eq!(check(&mut word), Startup::WaitForCommands);
+        assert_eq!(check(&mut word), Startup::AutoBoot);
+    }
+}
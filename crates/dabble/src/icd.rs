@@ -9,27 +9,40 @@ use crc::Digest;
 use postcard::ser_flavors::{Cobs, Slice};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DataChunk<'a> {
     pub data_addr: u32,
     pub sub_crc32: u32,
     pub data: &'a [u8],
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StartBootload {
     pub start_addr: u32,
     pub length: u32,
     pub crc32: u32,
+    /// The nonce the image's chunks are encrypted with, if they are
+    pub nonce: Option<[u8; 12]>,
+    /// The version the host says the image is, so that an old image can be
+    /// refused before it is sent. The version that counts is the one in the
+    /// slot's settings (see [`Slot::version_setting`]) when the load
+    /// completes, which signed images cover.
+    pub app_version: u32,
+    /// Load the image even if it is older than the device's minimum version.
+    /// Only honored for authenticated hosts.
+    pub allow_rollback: bool,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BootCommand {
     BootIfBootable,
+    /// Boot even if a load is in progress, or the application doesn't
+    /// check out. Devices that require signed images still refuse to boot
+    /// one that isn't.
     ForceBoot,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Request<'a> {
     Ping(u32),
     GetParameters,
@@ -43,6 +56,62 @@ pub enum Request<'a> {
     AbortBootload,
     IsBootable,
     Boot(BootCommand),
+    GetChallenge,
+    Authenticate { response: [u8; 32] },
+    GetSlots,
+}
+
+impl<'a> Request<'a> {
+    /// Does this request need an authenticated session, when the device
+    /// requires authentication?
+    ///
+    /// Anything that changes the device's state, or reads back flash
+    /// contents, does. A session ends when a load completes or is aborted,
+    /// or the device is told to boot.
+    pub fn requires_auth(&self) -> bool {
+        match self {
+            Request::Ping(_)
+            | Request::GetParameters
+            | Request::GetStatus
+            | Request::IsBootable
+            | Request::GetChallenge
+            | Request::Authenticate { .. }
+            | Request::GetSlots => false,
+            Request::StartBootload(_)
+            | Request::DataChunk(_)
+            | Request::CompleteBootload { .. }
+            | Request::GetSettings
+            | Request::WriteSettings { .. }
+            | Request::ReadRange { .. }
+            | Request::AbortBootload
+            | Request::Boot(_) => true,
+        }
+    }
+
+    /// Is it safe to send this request again, when it may already have
+    /// been acted on?
+    ///
+    /// Reads are, and so is a repeated `DataChunk`, which the device
+    /// recognizes. Anything else could be acted on twice.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Request::Ping(_)
+            | Request::GetParameters
+            | Request::GetStatus
+            | Request::IsBootable
+            | Request::GetSlots
+            | Request::GetSettings
+            | Request::ReadRange { .. }
+            | Request::DataChunk(_) => true,
+            Request::StartBootload(_)
+            | Request::CompleteBootload { .. }
+            | Request::WriteSettings { .. }
+            | Request::AbortBootload
+            | Request::Boot(_)
+            | Request::GetChallenge
+            | Request::Authenticate { .. } => false,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -50,6 +119,10 @@ pub enum ResponseError {
     // StartBootload responses
     BadStartAddress,
     BadLength,
+    EncryptionRequired,
+    EncryptionNotSupported,
+    VersionRollback { current: u32, attempted: u32 },
+    // Also sent for a Boot that isn't forced
     BootloadInProgress,
 
     // DataChunk responses
@@ -70,9 +143,18 @@ pub enum ResponseError {
     BadRangeStart,
     BadRangeEnd,
     BadRangeLength { actual: u32, max: u32 },
+    // Refused by the device's read policy, or reads are disabled entirely
+    ReadDenied,
 
+    // Framing failures (COBS, CRC, postcard, or a frame too large for the
+    // device's buffer). The request was NOT acted upon. This is the only
+    // response sent for undecodable requests.
     LineNak(crate::machine::Error),
     Oops,
+
+    // Authentication
+    Unauthorized,
+    AuthNotSupported,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -90,6 +172,8 @@ pub enum Status {
         expected_crc32: u32,
     },
     AwaitingComplete,
+    /// The device will boot once this response has been sent
+    BootPending,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
@@ -99,6 +183,77 @@ pub struct Parameters {
     pub valid_flash_range: (u32, u32),
     pub valid_app_range: (u32, u32),
     pub read_max: u32,
+    /// A second application slot, for A/B updates. `valid_app_range` is slot A.
+    pub alt_app_range: Option<(u32, u32)>,
+}
+
+/// One of the application slots
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    /// The other slot
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    /// The range of flash this slot occupies, if the device has it
+    pub fn range(self, params: &Parameters) -> Option<(u32, u32)> {
+        match self {
+            Slot::A => Some(params.valid_app_range),
+            Slot::B => params.alt_app_range,
+        }
+    }
+
+    /// The name of the setting holding the length of this slot's application
+    pub fn len_setting(self) -> &'static [u8] {
+        match self {
+            Slot::A => b"app_len",
+            Slot::B => b"app_len_b",
+        }
+    }
+
+    /// The name of the setting holding the CRC of this slot's application
+    pub fn crc_setting(self) -> &'static [u8] {
+        match self {
+            Slot::A => b"app_crc",
+            Slot::B => b"app_crc_b",
+        }
+    }
+
+    /// The name of the setting putting this slot's application on trial. Its
+    /// value is how many times it may be booted before it must confirm
+    /// itself (see [`crate::app::confirm_boot`]).
+    pub fn trial_setting(self) -> &'static [u8] {
+        match self {
+            Slot::A => b"app_trial",
+            Slot::B => b"app_trial_b",
+        }
+    }
+
+    /// The name of the setting holding the version of this slot's
+    /// application. Signatures cover it, along with the image.
+    pub fn version_setting(self) -> &'static [u8] {
+        match self {
+            Slot::A => crate::machine::VERSION_SETTING,
+            Slot::B => b"app_version_b",
+        }
+    }
+
+    /// The name of the setting holding the signature of this slot's application
+    #[cfg(feature = "ed25519")]
+    pub fn sig_setting(self) -> &'static [u8] {
+        match self {
+            Slot::A => crate::sig::SIG_SETTING,
+            Slot::B => b"app_sig_b",
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -127,19 +282,45 @@ pub enum Response<'a> {
         len: u32,
         data: &'a [u8],
     },
-    BadOverfillNak,
-    BadPostcardNak,
-    BadCrcNak,
     BootloadAborted,
     BootableStatus(Bootable),
     ConfirmBootCmd {
         will_boot: bool,
         boot_status: Bootable,
     },
+    Challenge {
+        nonce: [u8; 16],
+    },
+    Authenticated,
+    Slots {
+        active: Slot,
+        load: Slot,
+    },
+}
+
+/// A request, tagged with a sequence number chosen by the host.
+///
+/// This is what is actually sent over the wire.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestEnvelope<'a> {
+    pub seq: u32,
+    #[serde(borrow)]
+    pub req: Request<'a>,
+}
+
+/// A response, echoing the sequence number of the request it answers.
+///
+/// `seq` is only `None` when the request could not be decoded at all, e.g.
+/// for a [`ResponseError::LineNak`].
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ResponseEnvelope<'a> {
+    pub seq: Option<u32>,
+    #[serde(borrow)]
+    pub resp: Result<Response<'a>, ResponseError>,
 }
 
 #[cfg(feature = "use-std")]
-impl<'a> Request<'a> {
+impl<'a> RequestEnvelope<'a> {
     /// Encode a request to a vec.
     ///
     /// Does:
@@ -162,8 +343,8 @@ impl<'a> Request<'a> {
 }
 
 #[cfg(feature = "use-std")]
-impl<'a> Response<'a> {
-    /// Encode a request to a vec.
+impl<'a> ResponseEnvelope<'a> {
+    /// Encode a response to a vec.
     ///
     /// Does:
     ///
@@ -186,11 +367,11 @@ impl<'a> Response<'a> {
 
 #[inline]
 pub fn encode_resp_to_slice<'a, 'b>(
-    resp: &Result<Response<'a>, ResponseError>,
+    resp: &ResponseEnvelope<'a>,
     buf: &'b mut [u8],
 ) -> Result<&'b mut [u8], postcard::Error> {
     postcard::serialize_with_flavor::<
-        Result<Response<'a>, ResponseError>,
+        ResponseEnvelope<'a>,
         Crc32SerFlavor<Cobs<Slice<'b>>>,
         &'b mut [u8],
     >(
@@ -261,13 +442,13 @@ where
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Setting<'a> {
     pub name_ascii: &'a [u8],
     pub val: SettingVal<'a>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SettingVal<'a> {
     U32(u32),
     F32(f32),
@@ -275,6 +456,7 @@ pub enum SettingVal<'a> {
     AsciiSlice(&'a [u8]),
 }
 
+#[derive(Clone)]
 pub struct SettingsIter<'a> {
     remain: &'a [u8],
 }
@@ -322,10 +504,48 @@ pub fn settings_to_vec(items: &[Setting<'_>]) -> Vec<u8> {
     ser2
 }
 
-pub fn settings_from_raw(sli: &[u8]) -> Result<SettingsIter<'_>, ()> {
-    let (exp_crc, sli) = split_u32le(sli)?;
-    let (exp_len, sli) = split_u32le(sli)?;
-    let settings_bytes = sli.get(..(exp_len as usize)).ok_or(())?;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SettingsError {
+    /// The settings page is blank, or its length or CRC are wrong
+    Corrupted,
+    /// The settings don't fit in the space given
+    TooLong,
+    /// The setting belongs to the bootloader
+    Reserved,
+}
+
+/// Serialize settings into `buf`, in the same format as [`settings_to_vec`]
+pub fn settings_to_slice<'a, 'b>(
+    items: impl Iterator<Item = Setting<'b>>,
+    buf: &'a mut [u8],
+) -> Result<&'a [u8], SettingsError> {
+    if buf.len() < 8 {
+        return Err(SettingsError::TooLong);
+    }
+    let (header, body) = buf.split_at_mut(8);
+    let mut used = 0;
+    for stg in items {
+        let remain = body.get_mut(used..).ok_or(SettingsError::TooLong)?;
+        used += postcard::to_slice(&stg, remain)
+            .map_err(|_| SettingsError::TooLong)?
+            .len();
+    }
+
+    let len = used as u32;
+    let mut digest = CRC.digest();
+    digest.update(&len.to_le_bytes());
+    digest.update(&body[..used]);
+    header[..4].copy_from_slice(&digest.finalize().to_le_bytes());
+    header[4..].copy_from_slice(&len.to_le_bytes());
+    Ok(&buf[..8 + used])
+}
+
+pub fn settings_from_raw(sli: &[u8]) -> Result<SettingsIter<'_>, SettingsError> {
+    let (exp_crc, sli) = split_u32le(sli).ok_or(SettingsError::Corrupted)?;
+    let (exp_len, sli) = split_u32le(sli).ok_or(SettingsError::Corrupted)?;
+    let settings_bytes = sli
+        .get(..(exp_len as usize))
+        .ok_or(SettingsError::Corrupted)?;
     let mut digest = CRC.digest();
     digest.update(&exp_len.to_le_bytes());
     digest.update(settings_bytes);
@@ -336,24 +556,26 @@ pub fn settings_from_raw(sli: &[u8]) -> Result<SettingsIter<'_>, ()> {
             remain: settings_bytes,
         })
     } else {
-        Err(())
+        Err(SettingsError::Corrupted)
     }
 }
 
 #[inline]
-pub fn split_u32le(sli: &[u8]) -> Result<(u32, &[u8]), ()> {
+pub fn split_u32le(sli: &[u8]) -> Option<(u32, &[u8])> {
     if sli.len() < 4 {
-        return Err(());
+        return None;
     }
     let (bytes, remain) = sli.split_at(4);
     let mut buf = [0u8; 4];
     buf.copy_from_slice(bytes);
-    Ok((u32::from_le_bytes(buf), remain))
+    Some((u32::from_le_bytes(buf), remain))
 }
 
 #[cfg(test)]
 pub mod test {
-    use crate::icd::{settings_from_raw, settings_to_vec, Setting, SettingVal};
+    use crate::icd::{
+        settings_from_raw, settings_to_slice, settings_to_vec, Setting, SettingVal, SettingsError,
+    };
 
     #[test]
     fn settings_smoke() {
@@ -389,5 +611,16 @@ pub mod test {
         des_stgs.iter().zip(stgs).for_each(|(des, exp)| {
             assert_eq!(des, exp);
         });
+
+        // Serializing without an allocator gets the same thing
+        let mut buf = [0u8; 64];
+        let stgs_slice = settings_to_slice(stgs.iter().cloned(), &mut buf).unwrap();
+        assert_eq!(stgs_slice, stgs_vec.as_slice());
+
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            settings_to_slice(stgs.iter().cloned(), &mut buf),
+            Err(SettingsError::TooLong)
+        );
     }
 }
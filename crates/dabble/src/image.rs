@@ -0,0 +1,155 @@
+//! Self-describing application images
+//!
+//! An image may begin with a fixed size header describing itself, so the
+//! bootloader can still validate it if the settings page is lost. The
+//! application's vector table comes straight after the header, which is
+//! padded to 512 bytes so the table is aligned as `VTOR` requires, for up
+//! to 128 vectors. All fields are little-endian:
+//!
+//! | Offset | Size | Field                                                  |
+//! | :----- | :--- | :----------------------------------------------------- |
+//! | 0      | 4    | Magic, `b"DABL"`                                       |
+//! | 4      | 4    | Header version, currently 1                            |
+//! | 8      | 4    | Image length, including this header                    |
+//! | 12     | 4    | CRC32 of the image, after this header                  |
+//! | 16     | 4    | Application version                                    |
+//! | 20     | 4    | Load address: where the vector table is, after this    |
+//! |        |      | header, in the device's memory map                     |
+//! | 24     | 4    | Entry point, matching the vector table's reset vector  |
+//! | 28     | 20   | Build ID, e.g. a git commit hash                       |
+//! | 48     | 12   | Reserved, zero                                         |
+//! | 60     | 4    | CRC32 of the previous 60 bytes of the header           |
+//! | 64     | 448  | Padding, `0xFF`                                        |
+
+use crate::CRC;
+
+/// The size of the header, including its padding, at the start of the
+/// application region
+pub const HEADER_LEN: usize = 512;
+
+/// Marks the start of an image header
+pub const MAGIC: u32 = u32::from_le_bytes(*b"DABL");
+
+/// The header format this version of dabble produces and understands
+pub const HEADER_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageHeader {
+    pub image_len: u32,
+    pub image_crc: u32,
+    pub app_version: u32,
+    pub load_addr: u32,
+    pub entry_point: u32,
+    pub build_id: [u8; 20],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderError {
+    /// Fewer than `HEADER_LEN` bytes were given
+    TooShort,
+    /// There is no header here
+    BadMagic,
+    /// The header is from a newer (or older) version of dabble
+    UnsupportedVersion(u32),
+    /// The header is corrupted
+    BadHeaderCrc,
+}
+
+impl ImageHeader {
+    /// Parse the header at the start of `data`
+    pub fn parse(data: &[u8]) -> Result<Self, HeaderError> {
+        let hdr: &[u8; HEADER_LEN] = match data.get(..HEADER_LEN) {
+            Some(hdr) => hdr.try_into().unwrap(),
+            None => return Err(HeaderError::TooShort),
+        };
+        let u32_at = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&hdr[offset..][..4]);
+            u32::from_le_bytes(bytes)
+        };
+
+        if u32_at(0) != MAGIC {
+            return Err(HeaderError::BadMagic);
+        }
+        if u32_at(4) != HEADER_VERSION {
+            return Err(HeaderError::UnsupportedVersion(u32_at(4)));
+        }
+        if u32_at(60) != CRC.checksum(&hdr[..60]) {
+            return Err(HeaderError::BadHeaderCrc);
+        }
+
+        let mut build_id = [0u8; 20];
+        build_id.copy_from_slice(&hdr[28..48]);
+        Ok(ImageHeader {
+            image_len: u32_at(8),
+            image_crc: u32_at(12),
+            app_version: u32_at(16),
+            load_addr: u32_at(20),
+            entry_point: u32_at(24),
+            build_id,
+        })
+    }
+
+    /// Serialize the header, to be placed at the start of the image
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut hdr = [0xFFu8; HEADER_LEN];
+        hdr[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        hdr[4..8].copy_from_slice(&HEADER_VERSION.to_le_bytes());
+        hdr[8..12].copy_from_slice(&self.image_len.to_le_bytes());
+        hdr[12..16].copy_from_slice(&self.image_crc.to_le_bytes());
+        hdr[16..20].copy_from_slice(&self.app_version.to_le_bytes());
+        hdr[20..24].copy_from_slice(&self.load_addr.to_le_bytes());
+        hdr[24..28].copy_from_slice(&self.entry_point.to_le_bytes());
+        hdr[28..48].copy_from_slice(&self.build_id);
+        hdr[48..60].fill(0);
+        let crc = CRC.checksum(&hdr[..60]);
+        hdr[60..64].copy_from_slice(&crc.to_le_bytes());
+        hdr
+    }
+}
+
+/// Put a header in front of an application, filling in its length and CRC
+#[cfg(feature = "use-std")]
+pub fn with_header(app: &[u8], mut header: ImageHeader) -> Vec<u8> {
+    header.image_len = (HEADER_LEN + app.len()) as u32;
+    header.image_crc = CRC.checksum(app);
+    let mut image = header.to_bytes().to_vec();
+    image.extend_from_slice(app);
+    image
+}
+
+#[cfg(all(test, feature = "use-std"))]
+mod test {
+    use super::{with_header, HeaderError, ImageHeader, HEADER_LEN};
+
+    #[test]
+    fn round_trip() {
+        let header = ImageHeader {
+            image_len: 0,
+            image_crc: 0,
+            app_version: 7,
+            load_addr: 0x0800_4040,
+            entry_point: 0x0800_4141,
+            build_id: [0xAB; 20],
+        };
+        let image = with_header(&[1, 2, 3, 4], header);
+        assert_eq!(image.len(), HEADER_LEN + 4);
+
+        let parsed = ImageHeader::parse(&image).unwrap();
+        assert_eq!(parsed.image_len, HEADER_LEN as u32 + 4);
+        assert_eq!(parsed.app_version, 7);
+        assert_eq!(parsed.build_id, [0xAB; 20]);
+
+        let mut corrupted = image.clone();
+        corrupted[17] ^= 0x01;
+        assert_eq!(
+            ImageHeader::parse(&corrupted),
+            Err(HeaderError::BadHeaderCrc)
+        );
+        assert_eq!(
+            ImageHeader::parse(&[0xFF; HEADER_LEN]),
+            Err(HeaderError::BadMagic)
+        );
+        assert_eq!(ImageHeader::parse(&image[..10]), Err(HeaderError::TooShort));
+    }
+}
@@ -2,7 +2,26 @@
 
 use crc::{Crc, CRC_32_CKSUM};
 
+pub mod app;
+#[cfg(feature = "tokio")]
+pub mod async_client;
+#[cfg(feature = "auth")]
+pub mod auth;
+#[cfg(feature = "use-std")]
+pub mod client;
+#[cfg(feature = "encrypt")]
+pub mod crypt;
+pub mod handoff;
 pub mod icd;
+pub mod image;
 pub mod machine;
+#[cfg(feature = "use-std")]
+pub mod mock;
+#[cfg(feature = "async")]
+pub mod runner;
+#[cfg(feature = "ed25519")]
+pub mod sig;
+pub mod transport;
+pub mod vectors;
 
 pub const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
@@ -2,37 +2,118 @@ use core::mem::replace;
 
 use crate::{
     icd::{
-        settings_from_raw, BootCommand, DataChunk, Parameters, Request, Response, ResponseError,
-        Setting, SettingVal, StartBootload, Status,
+        settings_from_raw, BootCommand, DataChunk, Parameters, Request, RequestEnvelope, Response,
+        ResponseEnvelope, ResponseError, Setting, SettingVal, Slot, StartBootload, Status,
     },
+    image::{ImageHeader, HEADER_LEN},
+    vectors::MemoryMap,
     CRC,
 };
 use crc::Digest;
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "ed25519")]
+use sha2::{Digest as _, Sha512};
 
+/// Errors when decoding a frame off the wire
+///
+/// On the device, these are reported back to the host as
+/// [`ResponseError::LineNak`].
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Error {
+    /// The frame was too short to contain a message and CRC
     Underfill,
+    /// The frame was too long for the receive buffer
     Overfill,
+    /// The frame was intact, but did not contain a valid message
     PostcardDecode,
+    /// The frame was not valid COBS
     Cobs,
-    Crc { expected: u32, actual: u32 },
+    /// The frame was corrupted
+    Crc {
+        expected: u32,
+        actual: u32,
+    },
     LogicError,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Bootable {
     Unsure,
     NoMissingSettings,
     NoDuplicateSettings,
     NoInvalidSettings,
     NoInvalidCrc,
+    NoBadSignature,
+    NoFailedTrial,
+    NoInvalidVectorTable,
     Yes { crc32: u32, length: usize },
 }
 
 pub trait Flash {
     const PARAMETERS: Parameters;
 
+    /// The Ed25519 public key that application images must be signed with
+    #[cfg(feature = "ed25519")]
+    const APP_PUBLIC_KEY: [u8; 32];
+
+    /// The pre-shared key that hosts must know to authenticate. This must be
+    /// stored outside of the application region and settings page, so it
+    /// can't be replaced by loading an image or writing settings.
+    #[cfg(feature = "auth")]
+    fn auth_key(&mut self) -> [u8; 32];
+
+    /// Fill `buf` with unpredictable bytes, used to challenge the host
+    #[cfg(feature = "auth")]
+    fn fill_random(&mut self, buf: &mut [u8]);
+
+    /// The key that application images are encrypted with. Like the auth
+    /// key, this must be stored outside of anything the host can write.
+    #[cfg(feature = "encrypt")]
+    fn encryption_key(&mut self) -> [u8; 32];
+
+    /// The oldest application version that may be loaded. Like the auth key,
+    /// this must be stored outside of anything the host can write.
+    ///
+    /// There is no default, so rollback protection can't be left off by
+    /// accident. Hardware that really has nowhere to keep it can return 0,
+    /// and ignore `set_min_app_version`.
+    fn min_app_version(&mut self) -> u32;
+
+    /// Raise the oldest application version that may be loaded, after a
+    /// newer application has been loaded
+    fn set_min_app_version(&mut self, version: u32);
+
+    /// Which application slot is active. Like the minimum version, this must
+    /// be stored outside of anything the host can write.
+    ///
+    /// Only needed for devices with two slots (`Parameters::alt_app_range`).
+    fn active_slot(&mut self) -> Slot {
+        Slot::A
+    }
+
+    /// Switch the active application slot, after a new application has been
+    /// loaded into it, or to fall back to the previous one
+    fn set_active_slot(&mut self, _slot: Slot) {}
+
+    /// How many times the active application has been booted while on
+    /// trial. Like the minimum version, this must be stored outside of
+    /// anything the host can write, and survive a reset.
+    ///
+    /// There is no default, as a count that never goes up means trials
+    /// never fail.
+    fn boot_attempts(&mut self) -> u32;
+
+    /// Record a boot attempt, or reset the count for a new application
+    fn set_boot_attempts(&mut self, attempts: u32);
+
+    /// The range of flash holding the settings page, if it is readable with
+    /// `read_range`. Only used by `ReadPolicy::AppAndSettings`.
+    const SETTINGS_RANGE: Option<(u32, u32)> = None;
+
+    /// Where flash and RAM are, so the application's vector table can be
+    /// checked before booting it. If `None`, it isn't checked.
+    const MEMORY_MAP: Option<MemoryMap> = None;
+
     /// Program the following block of data to the address starting at start
     fn flash_range(&mut self, start: u32, data: &[u8]);
 
@@ -50,19 +131,60 @@ pub trait Flash {
     /// Read a given range of flash data
     fn read_range(&mut self, start_addr: u32, len: u32) -> &[u8];
 
-    /// Boot to the application
+    /// Boot to the application in the active slot, setting VTOR to, and
+    /// jumping through, the vector table at [`Flash::app_vectors`].
+    ///
+    /// This is called through [`Machine::boot`], which first switches to
+    /// the slot that [`Flash::is_bootable`] describes.
     fn boot(&mut self) -> !;
 
+    /// Where the active slot's application has its vector table, for
+    /// `boot` to jump to: straight after its image header (see
+    /// [`crate::image`]), if it has one
+    fn app_vectors(&mut self) -> u32 {
+        let (start, _) = match self.active_slot().range(&Self::PARAMETERS) {
+            Some(range) => range,
+            None => Self::PARAMETERS.valid_app_range,
+        };
+        match find_header(self, start) {
+            Some(_) => start + HEADER_LEN as u32,
+            None => start,
+        }
+    }
+
     /// Is the system currently capable of booting into the application?
+    ///
+    /// With two slots, if the active slot isn't bootable but the other one
+    /// is, this describes the other one, which [`Machine::boot`] falls back
+    /// to. Nothing is changed until then.
+    ///
+    /// An application on trial that has used up its boot attempts isn't
+    /// bootable either.
     fn is_bootable(&mut self) -> Bootable {
-        let pre_check = get_app_info(self.read_settings_raw(), &Self::PARAMETERS);
-        let (app_crc, app_len) = match pre_check {
-            Bootable::Yes { crc32, length } => (crc32, length as u32),
-            nope => return nope,
+        boot_slot(self).1
+    }
+
+    /// Is the application in the given slot bootable?
+    ///
+    /// If the settings page doesn't describe an application, this falls back
+    /// to an image header (see [`crate::image`]), unless images must be signed.
+    fn is_slot_bootable(&mut self, slot: Slot) -> Bootable {
+        let slot_range = match slot.range(&Self::PARAMETERS) {
+            Some(range) => range,
+            None => return Bootable::NoMissingSettings,
+        };
+        let info = match get_app_info(self.read_settings_raw(), &Self::PARAMETERS, slot) {
+            Ok(info) => info,
+            #[cfg(not(feature = "ed25519"))]
+            Err(Bootable::NoMissingSettings) => return bootable_from_header(self, slot_range),
+            Err(nope) => return nope,
         };
+        let app_len = info.length;
 
         let mut digest = CRC.digest();
-        let start = Self::PARAMETERS.valid_app_range.0;
+        #[cfg(feature = "ed25519")]
+        let mut hasher = Sha512::new();
+        let start = slot_range.0;
         let end = start + app_len;
         let chunk_len = Self::PARAMETERS.data_chunk_size;
 
@@ -70,49 +192,192 @@ pub trait Flash {
         while cur < end {
             let cur_page = self.read_range(cur, chunk_len);
             digest.update(cur_page);
+            #[cfg(feature = "ed25519")]
+            hasher.update(cur_page);
             cur = cur.saturating_add(chunk_len);
         }
 
         let act_crc = digest.finalize();
-        if act_crc == app_crc {
-            Bootable::Yes {
-                crc32: act_crc,
-                length: app_len as usize,
-            }
-        } else {
-            Bootable::NoInvalidCrc
+        if act_crc != info.crc32 {
+            return Bootable::NoInvalidCrc;
+        }
+
+        // The version is signed too, so it can't be raised to get an old
+        // image past the rollback check
+        #[cfg(feature = "ed25519")]
+        hasher.update(app_version(self.read_settings_raw(), slot).to_le_bytes());
+        #[cfg(feature = "ed25519")]
+        if !crate::sig::verify(&Self::APP_PUBLIC_KEY, &info.signature, hasher) {
+            return Bootable::NoBadSignature;
+        }
+
+        let header = find_header(self, slot_range.0);
+        if let Err(nope) = check_entry(self, slot_range, header.as_ref()) {
+            return nope;
+        }
+
+        Bootable::Yes {
+            crc32: act_crc,
+            length: app_len as usize,
+        }
+    }
+}
+
+/// The slot to boot, and whether it is bootable: the active one, unless it
+/// isn't bootable and the other one is
+fn boot_slot<HW: Flash + ?Sized>(hw: &mut HW) -> (Slot, Bootable) {
+    let active = hw.active_slot();
+    let failed_trial = match trial_limit(hw.read_settings_raw(), active) {
+        Some(limit) => hw.boot_attempts() >= limit,
+        None => false,
+    };
+    let status = if failed_trial {
+        Bootable::NoFailedTrial
+    } else {
+        hw.is_slot_bootable(active)
+    };
+    if matches!(status, Bootable::Yes { .. }) || HW::PARAMETERS.alt_app_range.is_none() {
+        return (active, status);
+    }
+
+    // The previous application may still be intact
+    match hw.is_slot_bootable(active.other()) {
+        yes @ Bootable::Yes { .. } => (active.other(), yes),
+        _ => (active, status),
+    }
+}
+
+/// Check the vector table at `addr`, within `slot_range`, if the hardware
+/// says where its memory is
+fn vectors_ok<HW: Flash + ?Sized>(hw: &mut HW, slot_range: (u32, u32), addr: u32) -> bool {
+    let map = match HW::MEMORY_MAP {
+        Some(map) => map,
+        None => return true,
+    };
+    let app = (
+        map.flash_base.wrapping_add(slot_range.0),
+        map.flash_base.wrapping_add(slot_range.1),
+    );
+    crate::vectors::check(hw.read_range(addr, 8), map.ram, app).is_ok()
+}
+
+/// The image header at `start`, if there is one
+fn find_header<HW: Flash + ?Sized>(hw: &mut HW, start: u32) -> Option<ImageHeader> {
+    ImageHeader::parse(hw.read_range(start, HEADER_LEN as u32)).ok()
+}
+
+/// Check where an application in `slot_range` will start: its vector
+/// table, which follows `header` if it has one, and the header's own idea
+/// of where that is
+fn check_entry<HW: Flash + ?Sized>(
+    hw: &mut HW,
+    slot_range: (u32, u32),
+    header: Option<&ImageHeader>,
+) -> Result<(), Bootable> {
+    let vectors = match header {
+        Some(_) => slot_range.0 + HEADER_LEN as u32,
+        None => slot_range.0,
+    };
+    if !vectors_ok(hw, slot_range, vectors) {
+        return Err(Bootable::NoInvalidVectorTable);
+    }
+
+    if let (Some(header), Some(map)) = (header, HW::MEMORY_MAP) {
+        let mut reset = [0u8; 4];
+        reset.copy_from_slice(&hw.read_range(vectors + 4, 4)[..4]);
+        let load_ok = header.load_addr == map.flash_base.wrapping_add(vectors);
+        let entry_ok = header.entry_point == u32::from_le_bytes(reset);
+        if !load_ok || !entry_ok {
+            return Err(Bootable::NoInvalidSettings);
         }
     }
+    Ok(())
+}
+
+/// Check an application that describes itself with an image header
+#[cfg(not(feature = "ed25519"))]
+fn bootable_from_header<HW: Flash + ?Sized>(hw: &mut HW, slot_range: (u32, u32)) -> Bootable {
+    let (start, end) = slot_range;
+    let header = match find_header(hw, start) {
+        Some(header) => header,
+        // No header either
+        None => return Bootable::NoMissingSettings,
+    };
+
+    if header.image_len < HEADER_LEN as u32 || header.image_len > (end - start) {
+        return Bootable::NoInvalidSettings;
+    }
+
+    let mut digest = CRC.digest();
+    let end = start + header.image_len;
+    let chunk_len = HW::PARAMETERS.data_chunk_size;
+    let mut cur = start + HEADER_LEN as u32;
+    while cur < end {
+        let len = chunk_len.min(end - cur);
+        digest.update(hw.read_range(cur, len));
+        cur += len;
+    }
+
+    if digest.finalize() != header.image_crc {
+        return Bootable::NoInvalidCrc;
+    }
+    if let Err(nope) = check_entry(hw, slot_range, Some(&header)) {
+        return nope;
+    }
+    Bootable::Yes {
+        crc32: header.image_crc,
+        length: header.image_len as usize,
+    }
 }
 
-fn get_app_info(raw_stg: &[u8], params: &Parameters) -> Bootable {
+/// The application image, as described by the settings page
+struct AppInfo {
+    crc32: u32,
+    length: u32,
+    #[cfg(feature = "ed25519")]
+    signature: [u8; 64],
+}
+
+fn get_app_info(raw_stg: &[u8], params: &Parameters, slot: Slot) -> Result<AppInfo, Bootable> {
     let mut app_len = None;
     let mut app_crc = None;
+    #[cfg(feature = "ed25519")]
+    let mut app_sig = None;
 
     let settings_iter = match settings_from_raw(raw_stg) {
         Ok(si) => si,
-        Err(_) => return Bootable::NoMissingSettings,
+        Err(_) => return Err(Bootable::NoMissingSettings),
     };
     for stg in settings_iter {
         match stg {
             Setting {
-                name_ascii: b"app_len",
+                name_ascii,
                 val: SettingVal::U32(len),
-            } => {
+            } if name_ascii == slot.len_setting() => {
                 if app_len.is_some() {
-                    return Bootable::NoDuplicateSettings;
+                    return Err(Bootable::NoDuplicateSettings);
                 }
                 app_len = Some(len);
             }
             Setting {
-                name_ascii: b"app_crc",
+                name_ascii,
                 val: SettingVal::U32(crc),
-            } => {
+            } if name_ascii == slot.crc_setting() => {
                 if app_crc.is_some() {
-                    return Bootable::NoDuplicateSettings;
+                    return Err(Bootable::NoDuplicateSettings);
                 }
                 app_crc = Some(crc);
             }
+            #[cfg(feature = "ed25519")]
+            Setting {
+                name_ascii,
+                val: SettingVal::ByteSlice(sig),
+            } if name_ascii == slot.sig_setting() => {
+                if app_sig.is_some() {
+                    return Err(Bootable::NoDuplicateSettings);
+                }
+                app_sig = Some(sig);
+            }
             _ => {}
         }
     }
@@ -120,15 +385,26 @@ fn get_app_info(raw_stg: &[u8], params: &Parameters) -> Bootable {
     let app_len = if let Some(len) = app_len {
         len
     } else {
-        return Bootable::NoMissingSettings;
+        return Err(Bootable::NoMissingSettings);
     };
     let app_crc = if let Some(crc) = app_crc {
         crc
     } else {
-        return Bootable::NoMissingSettings;
+        return Err(Bootable::NoMissingSettings);
+    };
+    #[cfg(feature = "ed25519")]
+    let signature: [u8; 64] = match app_sig {
+        Some(sig) => match sig.try_into() {
+            Ok(sig) => sig,
+            Err(_) => return Err(Bootable::NoInvalidSettings),
+        },
+        None => return Err(Bootable::NoMissingSettings),
     };
 
-    let (start, end) = params.valid_app_range;
+    let (start, end) = match slot.range(params) {
+        Some(range) => range,
+        None => return Err(Bootable::NoMissingSettings),
+    };
     let chunk_len = params.data_chunk_size;
     let ttl_len = end - start;
 
@@ -150,13 +426,135 @@ fn get_app_info(raw_stg: &[u8], params: &Parameters) -> Bootable {
     let not_pow2 = !app_len.is_power_of_two();
     let fail_check = too_long || too_short || not_pow2;
     if fail_check {
-        return Bootable::NoInvalidSettings;
+        return Err(Bootable::NoInvalidSettings);
     }
 
-    Bootable::Yes {
+    Ok(AppInfo {
         crc32: app_crc,
-        length: app_len as usize,
+        length: app_len,
+        #[cfg(feature = "ed25519")]
+        signature,
+    })
+}
+
+/// How chunks of the image being loaded are decrypted
+#[cfg(feature = "encrypt")]
+struct ImageKey {
+    key: [u8; 32],
+    nonce: [u8; 12],
+}
+
+/// How chunks of the image being loaded are decrypted. They aren't.
+#[cfg(not(feature = "encrypt"))]
+struct ImageKey;
+
+/// The size of the stack buffer chunks are decrypted through
+#[cfg(feature = "encrypt")]
+const DECRYPT_BLOCK: usize = 64;
+
+impl ImageKey {
+    #[cfg(feature = "encrypt")]
+    fn new<HW: Flash>(hw: &mut HW, nonce: Option<[u8; 12]>) -> Result<Self, ResponseError> {
+        match nonce {
+            Some(nonce) => Ok(ImageKey {
+                key: hw.encryption_key(),
+                nonce,
+            }),
+            None => Err(ResponseError::EncryptionRequired),
+        }
+    }
+
+    #[cfg(not(feature = "encrypt"))]
+    fn new<HW: Flash>(_hw: &mut HW, nonce: Option<[u8; 12]>) -> Result<Self, ResponseError> {
+        match nonce {
+            Some(_) => Err(ResponseError::EncryptionNotSupported),
+            None => Ok(ImageKey),
+        }
+    }
+
+    /// Call `f` with the plaintext of `data`, which starts `offset` bytes
+    /// into the image, along with each piece's position within `data`.
+    ///
+    /// Chunks are too large to decrypt onto the stack in one go, so this
+    /// is done a small block at a time.
+    #[cfg(feature = "encrypt")]
+    fn plaintext(&self, offset: u32, data: &[u8], mut f: impl FnMut(usize, &[u8])) {
+        let mut buf = [0u8; DECRYPT_BLOCK];
+        for (i, block) in data.chunks(DECRYPT_BLOCK).enumerate() {
+            let pos = i * DECRYPT_BLOCK;
+            let buf = &mut buf[..block.len()];
+            buf.copy_from_slice(block);
+            crate::crypt::apply_keystream(&self.key, &self.nonce, offset + pos as u32, buf);
+            f(pos, buf);
+        }
+    }
+
+    #[cfg(not(feature = "encrypt"))]
+    fn plaintext(&self, _offset: u32, data: &[u8], mut f: impl FnMut(usize, &[u8])) {
+        f(0, data)
+    }
+
+    /// The CRC of the plaintext of `data`
+    fn checksum(&self, offset: u32, data: &[u8]) -> u32 {
+        let mut digest = CRC.digest();
+        self.plaintext(offset, data, |_, block| digest.update(block));
+        digest.finalize()
+    }
+}
+
+/// How many boots the application in `slot` gets to confirm itself, if it
+/// is on trial
+fn trial_limit(settings_raw: &[u8], slot: Slot) -> Option<u32> {
+    let mut settings_iter = match settings_from_raw(settings_raw) {
+        Ok(si) => si,
+        Err(_) => return None,
+    };
+    settings_iter.find_map(|stg| match stg {
+        Setting {
+            name_ascii,
+            val: SettingVal::U32(limit),
+        } if name_ascii == slot.trial_setting() => Some(limit),
+        _ => None,
+    })
+}
+
+/// The name of the setting holding slot A's application version (see
+/// [`Slot::version_setting`])
+pub const VERSION_SETTING: &[u8] = b"app_version";
+
+/// Get the version of the application in `slot` from the settings page.
+/// Unversioned applications are treated as version 0.
+fn app_version(settings_raw: &[u8], slot: Slot) -> u32 {
+    let settings_iter = match settings_from_raw(settings_raw) {
+        Ok(si) => si,
+        Err(_) => return 0,
+    };
+    for stg in settings_iter {
+        match stg {
+            Setting {
+                name_ascii,
+                val: SettingVal::U32(version),
+            } if name_ascii == slot.version_setting() => return version,
+            _ => {}
+        }
     }
+    0
+}
+
+/// The name of the setting holding how long the bootloader waits for a host,
+/// in milliseconds, before booting a bootable application
+pub const BOOT_WINDOW_SETTING: &[u8] = b"boot_window_ms";
+
+fn boot_window_ms(settings_raw: &[u8]) -> Option<u32> {
+    settings_from_raw(settings_raw)
+        .ok()?
+        .find_map(|stg| match stg {
+            Setting {
+                name_ascii: BOOT_WINDOW_SETTING,
+                val: SettingVal::U32(ms),
+            } => Some(ms),
+            _ => None,
+        })
 }
 
 struct BootLoadMeta {
@@ -165,6 +563,11 @@ struct BootLoadMeta {
     addr_current: u32,
     length: u32,
     exp_crc: u32,
+    /// Address and CRC of the most recently accepted chunk
+    last_chunk: Option<(u32, u32)>,
+    image_key: ImageKey,
+    allow_rollback: bool,
+    slot: Slot,
 }
 
 enum Mode {
@@ -173,6 +576,48 @@ enum Mode {
     BootPending,
 }
 
+/// A [`Mode`], without its contents
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ModeKind {
+    Idle,
+    BootLoad,
+    BootPending,
+}
+
+impl Mode {
+    fn kind(&self) -> ModeKind {
+        match self {
+            Mode::Idle => ModeKind::Idle,
+            Mode::BootLoad(_) => ModeKind::BootLoad,
+            Mode::BootPending => ModeKind::BootPending,
+        }
+    }
+}
+
+/// The modes the machine may be in after handling `req` in mode `from`.
+///
+/// This is every change of mode the handlers make, in one place. Requests
+/// that aren't listed never change the mode, and nothing leaves
+/// `BootPending`: the next `check_after_send` boots.
+fn transitions(from: ModeKind, req: &Request<'_>) -> &'static [ModeKind] {
+    use ModeKind::{BootLoad, BootPending, Idle};
+    match (from, req) {
+        (Idle, Request::StartBootload(_)) => &[Idle, BootLoad],
+        // Only if it will boot
+        (Idle, Request::Boot(_)) => &[Idle, BootPending],
+        (BootLoad, Request::DataChunk(_)) => &[BootLoad],
+        // An incomplete load carries on. Otherwise the load is over, and
+        // it only boots if asked to and it will.
+        (BootLoad, Request::CompleteBootload { .. }) => &[BootLoad, Idle, BootPending],
+        (BootLoad, Request::AbortBootload) => &[Idle],
+        // Only if forced
+        (BootLoad, Request::Boot(_)) => &[BootLoad, BootPending],
+        (Idle, _) => &[Idle],
+        (BootLoad, _) => &[BootLoad],
+        (BootPending, _) => &[BootPending],
+    }
+}
+
 #[allow(dead_code)]
 const fn stm32g031_params() -> Parameters {
     Parameters {
@@ -181,12 +626,38 @@ const fn stm32g031_params() -> Parameters {
         valid_flash_range: (0, 64 * 1024),
         valid_app_range: (16 * 1024, 64 * 1024),
         read_max: 2 * 1024,
+        alt_app_range: None,
     }
 }
 
+/// Which parts of flash may be read back with `Request::ReadRange`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadPolicy {
+    /// Only the application slot(s)
+    AppOnly,
+    /// The application slot(s), and the settings page (see `Flash::SETTINGS_RANGE`)
+    AppAndSettings,
+    /// Anywhere in the valid flash range, including the bootloader itself
+    Everything,
+    /// Nowhere, so decrypted images can't be read back
+    Nothing,
+}
+
+/// The host's authentication state
+#[cfg(feature = "auth")]
+struct Session {
+    /// The most recent challenge, if it hasn't been answered yet
+    nonce: Option<[u8; 16]>,
+    authenticated: bool,
+}
+
 pub struct Machine<HW: Flash> {
     mode: Mode,
     hardware: HW,
+    read_policy: ReadPolicy,
+    received_request: bool,
+    #[cfg(feature = "auth")]
+    session: Session,
 }
 
 impl<HW: Flash> Machine<HW> {
@@ -194,16 +665,90 @@ impl<HW: Flash> Machine<HW> {
         Self {
             mode: Mode::Idle,
             hardware: hw,
+            read_policy: if cfg!(feature = "encrypt") {
+                ReadPolicy::Nothing
+            } else {
+                ReadPolicy::Everything
+            },
+            received_request: false,
+            #[cfg(feature = "auth")]
+            session: Session {
+                nonce: None,
+                authenticated: false,
+            },
         }
     }
 
+    /// Restrict which parts of flash may be read with `Request::ReadRange`.
+    ///
+    /// Defaults to `ReadPolicy::Everything`, or `ReadPolicy::Nothing` with the
+    /// `encrypt` feature: images are stored decrypted, so any other policy
+    /// lets a host read them back in plaintext. With the `no-read-range`
+    /// feature, all reads are refused regardless of policy.
+    pub fn set_read_policy(&mut self, policy: ReadPolicy) {
+        self.read_policy = policy;
+    }
+
     /// This function should be called after sending.
     ///
     /// At the moment, all this does is reboot the device
     /// if a boot was requested
     pub fn check_after_send(&mut self) {
         if matches!(self.mode, Mode::BootPending) {
-            self.hardware.boot();
+            self.boot();
+        }
+    }
+
+    /// Has a valid request frame been received since startup?
+    ///
+    /// Bootloaders that boot automatically after a timeout use this to
+    /// tell whether a host is talking to them.
+    pub fn received_request(&self) -> bool {
+        self.received_request
+    }
+
+    /// How long to wait for a host before booting automatically, from
+    /// the [`BOOT_WINDOW_SETTING`] setting
+    pub fn boot_window_ms(&mut self) -> Option<u32> {
+        boot_window_ms(self.hardware.read_settings_raw())
+    }
+
+    pub fn hardware(&self) -> &HW {
+        &self.hardware
+    }
+
+    pub fn hardware_mut(&mut self) -> &mut HW {
+        &mut self.hardware
+    }
+
+    /// Is the application bootable? See [`Flash::is_bootable`].
+    pub fn is_bootable(&mut self) -> Bootable {
+        self.hardware.is_bootable()
+    }
+
+    /// Boot to the application that [`Machine::is_bootable`] describes,
+    /// counting the attempt if it is on trial
+    pub fn boot(&mut self) -> ! {
+        self.prepare_boot();
+        self.hardware.boot()
+    }
+
+    /// Fall back to the previous application if the active one can't be
+    /// booted, then count the attempt
+    fn prepare_boot(&mut self) {
+        let (slot, _) = boot_slot(&mut self.hardware);
+        if slot != self.hardware.active_slot() {
+            self.hardware.set_active_slot(slot);
+            self.hardware.set_boot_attempts(0);
+        }
+        self.count_boot_attempt();
+    }
+
+    fn count_boot_attempt(&mut self) {
+        let active = self.hardware.active_slot();
+        if trial_limit(self.hardware.read_settings_raw(), active).is_some() {
+            let attempts = self.hardware.boot_attempts();
+            self.hardware.set_boot_attempts(attempts.saturating_add(1));
         }
     }
 
@@ -211,30 +756,96 @@ impl<HW: Flash> Machine<HW> {
     ///
     /// Most messages have a dedicated handler function, located in the impl block below
     pub fn process<'a>(&mut self, buf: &'a mut [u8]) -> Option<&'a [u8]> {
-        let resp: Result<Response<'static>, ResponseError> = match crate::icd::decode_in_place::<
-            Request<'_>,
-        >(buf)
+        let (seq, resp) = match crate::icd::decode_in_place::<RequestEnvelope<'_>>(buf) {
+            Ok(RequestEnvelope { seq, req }) => {
+                self.received_request = true;
+                (Some(seq), self.dispatch(req))
+            }
+            Err(e) => (None, Err(ResponseError::LineNak(e))),
+        };
+        self.respond(seq, resp, buf)
+    }
+
+    fn dispatch(&mut self, req: Request<'_>) -> Result<Response<'static>, ResponseError> {
+        let before = self.mode.kind();
+        let allowed = transitions(before, &req);
+        let response = self.dispatch_inner(req);
+        let after = self.mode.kind();
+        debug_assert!(
+            allowed.contains(&after),
+            "mode change missing from the transition table"
+        );
+
+        // A session lasts for one load, or until a boot
+        let load_ended = before == ModeKind::BootLoad && after != ModeKind::BootLoad;
+        if load_ended || after == ModeKind::BootPending {
+            self.end_session();
+        }
+        response
+    }
+
+    /// Forget the authenticated host, if there is one, e.g. when the link
+    /// to it drops. The next host has to authenticate again.
+    pub fn end_session(&mut self) {
+        #[cfg(feature = "auth")]
         {
-            Ok(Request::Ping(n)) => Ok(Response::Pong(n)),
-            Ok(Request::GetParameters) => Ok(Response::Parameters(HW::PARAMETERS)),
-            Ok(Request::StartBootload(sb)) => self.handle_start_bootload(sb),
-            Ok(Request::DataChunk(dc)) => self.handle_data_chunk(dc),
-            Ok(Request::CompleteBootload { boot }) => self.handle_complete_bootload(boot),
-            Ok(Request::GetSettings) => Ok(Response::Settings { data: &[] }),
-            Ok(Request::WriteSettings { data }) => self.handle_write_settings(data),
-            Ok(Request::GetStatus) => self.handle_get_status(),
-            Ok(Request::ReadRange { start_addr, len }) => self.handle_read_range(start_addr, len),
-            Ok(Request::AbortBootload) => self.handle_abort_bootload(),
-            Ok(Request::IsBootable) => Ok(Response::BootableStatus(self.hardware.is_bootable())),
-            Ok(Request::Boot(cmd)) => self.handle_boot(cmd),
-            Err(e) => Err(ResponseError::LineNak(e)),
-        };
-        self.respond(resp, buf)
+            self.session = Session {
+                nonce: None,
+                authenticated: false,
+            };
+        }
+    }
+
+    fn dispatch_inner(&mut self, req: Request<'_>) -> Result<Response<'static>, ResponseError> {
+        #[cfg(feature = "auth")]
+        if req.requires_auth() && !self.session.authenticated {
+            return Err(ResponseError::Unauthorized);
+        }
+
+        match req {
+            Request::Ping(n) => Ok(Response::Pong(n)),
+            Request::GetParameters => Ok(Response::Parameters(HW::PARAMETERS)),
+            Request::StartBootload(sb) => self.handle_start_bootload(sb),
+            Request::DataChunk(dc) => self.handle_data_chunk(dc),
+            Request::CompleteBootload { boot } => self.handle_complete_bootload(boot),
+            Request::GetSettings => Ok(Response::Settings { data: &[] }),
+            Request::WriteSettings { data } => self.handle_write_settings(data),
+            Request::GetStatus => self.handle_get_status(),
+            Request::ReadRange { start_addr, len } => self.handle_read_range(start_addr, len),
+            Request::AbortBootload => self.handle_abort_bootload(),
+            Request::IsBootable => Ok(Response::BootableStatus(self.hardware.is_bootable())),
+            Request::Boot(cmd) => self.handle_boot(cmd),
+            Request::GetChallenge => self.handle_get_challenge(),
+            Request::Authenticate { response } => self.handle_authenticate(response),
+            Request::GetSlots => Ok(Response::Slots {
+                active: self.hardware.active_slot(),
+                load: self.load_slot(),
+            }),
+        }
+    }
+
+    /// The slot the next bootload will be written to: if there are two,
+    /// whichever one won't be booted, so the application that would be is
+    /// never erased
+    fn load_slot(&mut self) -> Slot {
+        match HW::PARAMETERS.alt_app_range {
+            Some(_) => boot_slot(&mut self.hardware).0.other(),
+            None => Slot::A,
+        }
+    }
+
+    /// Prepare a NAK for a frame that did not fit in the receive buffer.
+    ///
+    /// This should be called instead of `process` once the end of the
+    /// too-long frame has been received (and discarded).
+    pub fn nak_overfill<'a>(&mut self, buf: &'a mut [u8]) -> Option<&'a [u8]> {
+        self.respond(None, Err(ResponseError::LineNak(Error::Overfill)), buf)
     }
 
     #[inline]
     fn respond<'a>(
         &mut self,
+        seq: Option<u32>,
         msg: Result<Response<'static>, ResponseError>,
         buf: &'a mut [u8],
     ) -> Option<&'a [u8]> {
@@ -265,7 +876,7 @@ impl<HW: Flash> Machine<HW> {
             Err(err_msg) => Err(err_msg),
         };
 
-        crate::icd::encode_resp_to_slice(&msg, buf)
+        crate::icd::encode_resp_to_slice(&ResponseEnvelope { seq, resp: msg }, buf)
             .ok()
             .map(|b| &*b)
     }
@@ -303,16 +914,32 @@ impl<HW: Flash> Machine<HW> {
         &mut self,
         sb: StartBootload,
     ) -> (Result<Response<'static>, ResponseError>, Mode) {
-        if sb.start_addr != HW::PARAMETERS.valid_app_range.0 {
+        // Keep the application that would boot, when there is somewhere else to load
+        let slot = self.load_slot();
+        let (slot_start, slot_end) = match slot.range(&HW::PARAMETERS) {
+            Some(range) => range,
+            None => return (Err(ResponseError::Oops), Mode::Idle),
+        };
+        if sb.start_addr != slot_start {
             return (Err(ResponseError::BadStartAddress), Mode::Idle);
         }
-        let max_app_len = HW::PARAMETERS.valid_app_range.1 - HW::PARAMETERS.valid_app_range.0;
+        let max_app_len = slot_end - slot_start;
         let too_long = sb.length > max_app_len;
         let mask = HW::PARAMETERS.data_chunk_size - 1;
         let not_full = (sb.length & mask) != 0;
         if too_long || not_full {
             return (Err(ResponseError::BadLength), Mode::Idle);
         }
+        let image_key = match ImageKey::new(&mut self.hardware, sb.nonce) {
+            Ok(key) => key,
+            Err(e) => return (Err(e), Mode::Idle),
+        };
+        // Overriding is only allowed for authenticated hosts. With the `auth`
+        // feature, any host that can start a bootload is.
+        let allow_rollback = sb.allow_rollback && cfg!(feature = "auth");
+        if let Err(e) = self.check_version(sb.app_version, allow_rollback) {
+            return (Err(e), Mode::Idle);
+        }
 
         self.hardware.erase_range(sb.start_addr, sb.length);
 
@@ -324,6 +951,10 @@ impl<HW: Flash> Machine<HW> {
                 addr_current: sb.start_addr,
                 length: sb.length,
                 exp_crc: sb.crc32,
+                last_chunk: None,
+                image_key,
+                allow_rollback,
+                slot,
             }),
         )
     }
@@ -354,6 +985,29 @@ impl<HW: Flash> Machine<HW> {
         mut meta: BootLoadMeta,
         dc: DataChunk<'_>,
     ) -> (Result<Response<'static>, ResponseError>, Mode) {
+        // Is this a retransmission of the chunk we just accepted? This happens
+        // when our `ChunkAccepted` response gets lost. It's already in flash,
+        // so just acknowledge it again.
+        if let Some((last_addr, last_crc)) = meta.last_chunk {
+            let same_chunk = dc.data_addr == last_addr
+                && dc.sub_crc32 == last_crc
+                && dc.data.len() as u32 == HW::PARAMETERS.data_chunk_size
+                && meta
+                    .image_key
+                    .checksum(last_addr - meta.addr_start, dc.data)
+                    == last_crc;
+            if same_chunk {
+                return (
+                    Ok(Response::ChunkAccepted {
+                        data_addr: last_addr,
+                        data_len: HW::PARAMETERS.data_chunk_size,
+                        crc32: last_crc,
+                    }),
+                    Mode::BootLoad(meta),
+                );
+            }
+        }
+
         if dc.data_addr != meta.addr_current {
             return (
                 Err(ResponseError::SkippedRange {
@@ -376,7 +1030,9 @@ impl<HW: Flash> Machine<HW> {
             return (Err(ResponseError::TooManyChunks), Mode::BootLoad(meta));
         }
 
-        let calc_crc = CRC.checksum(dc.data);
+        // Check the (decrypted) chunk before writing any of it
+        let offset = dc.data_addr - meta.addr_start;
+        let calc_crc = meta.image_key.checksum(offset, dc.data);
         if calc_crc != dc.sub_crc32 {
             return (
                 Err(ResponseError::BadSubCrc {
@@ -387,9 +1043,12 @@ impl<HW: Flash> Machine<HW> {
             );
         }
 
-        self.hardware.flash_range(dc.data_addr, dc.data);
-        meta.digest_running.update(dc.data);
+        meta.image_key.plaintext(offset, dc.data, |pos, block| {
+            self.hardware.flash_range(dc.data_addr + pos as u32, block);
+            meta.digest_running.update(block);
+        });
         meta.addr_current += HW::PARAMETERS.data_chunk_size;
+        meta.last_chunk = Some((dc.data_addr, calc_crc));
 
         (
             Ok(Response::ChunkAccepted {
@@ -431,6 +1090,7 @@ impl<HW: Flash> Machine<HW> {
         boot_cmd: Option<BootCommand>,
     ) -> (Result<Response<'static>, ResponseError>, Mode) {
         let complete = meta.addr_current == (meta.addr_start + meta.length);
+        let version = app_version(self.hardware.read_settings_raw(), meta.slot);
         let response;
         let mode = if !complete {
             response = Err(ResponseError::IncompleteLoad {
@@ -446,14 +1106,31 @@ impl<HW: Flash> Machine<HW> {
                     actual: calc_crc,
                 });
                 Mode::Idle
+            } else if let Err(e) = self.check_version(version, meta.allow_rollback) {
+                // The host wrote a different version than it started with
+                response = Err(e);
+                Mode::Idle
             } else {
-                let boot_status = self.hardware.is_bootable();
-
-                let will_boot = match boot_cmd {
-                    Some(BootCommand::ForceBoot) => true,
-                    Some(BootCommand::BootIfBootable) => {
-                        matches!(boot_status, Bootable::Yes { .. })
+                // Only switch to the new application once it checks out
+                let boot_status = self.hardware.is_slot_bootable(meta.slot);
+                let is_bootable = matches!(boot_status, Bootable::Yes { .. });
+                if is_bootable {
+                    if meta.slot != self.hardware.active_slot() {
+                        self.hardware.set_active_slot(meta.slot);
                     }
+                    // A new application gets a fresh trial
+                    self.hardware.set_boot_attempts(0);
+                }
+
+                // Only a bootable image can raise the minimum, otherwise a
+                // bogus image could lock out every real one
+                let newer = version > self.hardware.min_app_version();
+                if newer && is_bootable {
+                    self.hardware.set_min_app_version(version);
+                }
+
+                let will_boot = match &boot_cmd {
+                    Some(cmd) => will_boot(cmd, &boot_status),
                     None => false,
                 };
 
@@ -472,6 +1149,15 @@ impl<HW: Flash> Machine<HW> {
         (response, mode)
     }
 
+    /// Check that an application is no older than the device's minimum version
+    fn check_version(&mut self, attempted: u32, allow_rollback: bool) -> Result<(), ResponseError> {
+        let current = self.hardware.min_app_version();
+        if attempted < current && !allow_rollback {
+            return Err(ResponseError::VersionRollback { current, attempted });
+        }
+        Ok(())
+    }
+
     /// Handles `Request::WriteSettings`
     fn handle_write_settings(&mut self, data: &[u8]) -> Result<Response<'static>, ResponseError> {
         if data.len() as u32 > HW::PARAMETERS.settings_max {
@@ -491,7 +1177,7 @@ impl<HW: Flash> Machine<HW> {
         Ok(Response::Status({
             match &self.mode {
                 Mode::Idle => Status::Idle,
-                Mode::BootPending => Status::Idle,
+                Mode::BootPending => Status::BootPending,
                 Mode::BootLoad(meta) => {
                     if meta.addr_start == meta.addr_current {
                         Status::Started {
@@ -520,19 +1206,51 @@ impl<HW: Flash> Machine<HW> {
         start_addr: u32,
         len: u32,
     ) -> Result<Response<'static>, ResponseError> {
+        if cfg!(feature = "no-read-range") {
+            return Err(ResponseError::ReadDenied);
+        }
+
         let start_ok = start_addr >= HW::PARAMETERS.valid_flash_range.0;
         if !start_ok {
             return Err(ResponseError::BadRangeStart);
         }
 
-        match start_addr.checked_add(len) {
-            Some(end) if end <= HW::PARAMETERS.valid_flash_range.1 => Ok(Response::ReadRange {
-                start_addr,
-                len,
-                data: &[],
-            }),
-            _ => Err(ResponseError::BadRangeEnd),
+        let end = match start_addr.checked_add(len) {
+            Some(end) if end <= HW::PARAMETERS.valid_flash_range.1 => end,
+            _ => return Err(ResponseError::BadRangeEnd),
+        };
+
+        // Any more wouldn't fit in a response
+        if len > HW::PARAMETERS.read_max {
+            return Err(ResponseError::BadRangeLength {
+                actual: len,
+                max: HW::PARAMETERS.read_max,
+            });
+        }
+
+        let within = |(rstart, rend): (u32, u32)| start_addr >= rstart && end <= rend;
+        let allowed = match self.read_policy {
+            ReadPolicy::AppOnly => {
+                within(HW::PARAMETERS.valid_app_range)
+                    || HW::PARAMETERS.alt_app_range.is_some_and(within)
+            }
+            ReadPolicy::AppAndSettings => {
+                within(HW::PARAMETERS.valid_app_range)
+                    || HW::PARAMETERS.alt_app_range.is_some_and(within)
+                    || HW::SETTINGS_RANGE.is_some_and(within)
+            }
+            ReadPolicy::Everything => true,
+            ReadPolicy::Nothing => false,
+        };
+        if !allowed {
+            return Err(ResponseError::ReadDenied);
         }
+
+        Ok(Response::ReadRange {
+            start_addr,
+            len,
+            data: &[],
+        })
     }
 
     /// Handles Request::AbortBootload
@@ -558,12 +1276,17 @@ impl<HW: Flash> Machine<HW> {
 
     /// Handles `Request::Boot`
     fn handle_boot(&mut self, cmd: BootCommand) -> Result<Response<'static>, ResponseError> {
+        // Booting now would abandon the load, so only do it if forced
+        let loading = matches!(self.mode, Mode::BootLoad(_));
+        if loading && !matches!(cmd, BootCommand::ForceBoot) {
+            return Err(ResponseError::BootloadInProgress);
+        }
+
         let boot_status = self.hardware.is_bootable();
-        let will_boot = match cmd {
-            BootCommand::BootIfBootable => matches!(boot_status, Bootable::Yes { .. }),
-            BootCommand::ForceBoot => true,
-        };
-        self.mode = Mode::BootPending;
+        let will_boot = will_boot(&cmd, &boot_status);
+        if will_boot {
+            self.mode = Mode::BootPending;
+        }
         Ok(Response::ConfirmBootCmd {
             will_boot,
             boot_status,
@@ -571,6 +1294,65 @@ impl<HW: Flash> Machine<HW> {
     }
 }
 
+/// Whether `cmd` boots the application, given its `status`.
+///
+/// With `ed25519`, only signed images may run, so `ForceBoot` overrides an
+/// unfinished load, but never the signature check.
+fn will_boot(cmd: &BootCommand, status: &Bootable) -> bool {
+    let bootable = matches!(status, Bootable::Yes { .. });
+    match cmd {
+        BootCommand::BootIfBootable => bootable,
+        BootCommand::ForceBoot => bootable || !cfg!(feature = "ed25519"),
+    }
+}
+
+/// Authentication Handler Methods
+///
+/// Without the `auth` feature, hosts never need to authenticate.
+impl<HW: Flash> Machine<HW> {
+    /// Handles `Request::GetChallenge`
+    #[cfg(feature = "auth")]
+    fn handle_get_challenge(&mut self) -> Result<Response<'static>, ResponseError> {
+        let mut nonce = [0u8; 16];
+        self.hardware.fill_random(&mut nonce);
+        self.session.nonce = Some(nonce);
+        Ok(Response::Challenge { nonce })
+    }
+
+    #[cfg(not(feature = "auth"))]
+    fn handle_get_challenge(&mut self) -> Result<Response<'static>, ResponseError> {
+        Err(ResponseError::AuthNotSupported)
+    }
+
+    /// Handles `Request::Authenticate`
+    #[cfg(feature = "auth")]
+    fn handle_authenticate(
+        &mut self,
+        response: [u8; 32],
+    ) -> Result<Response<'static>, ResponseError> {
+        // Each challenge can only be answered once
+        let nonce = match self.session.nonce.take() {
+            Some(nonce) => nonce,
+            None => return Err(ResponseError::Unauthorized),
+        };
+        let key = self.hardware.auth_key();
+        self.session.authenticated = crate::auth::verify(&key, &nonce, &response);
+        if self.session.authenticated {
+            Ok(Response::Authenticated)
+        } else {
+            Err(ResponseError::Unauthorized)
+        }
+    }
+
+    #[cfg(not(feature = "auth"))]
+    fn handle_authenticate(
+        &mut self,
+        _response: [u8; 32],
+    ) -> Result<Response<'static>, ResponseError> {
+        Err(ResponseError::AuthNotSupported)
+    }
+}
+
 #[cfg(test)]
 pub mod feat_test {
     #[test]
@@ -591,121 +1373,231 @@ pub mod test {
     use super::Flash;
     use crate::{
         icd::{
-            decode_in_place, settings_to_vec, DataChunk, Parameters, Request, Response,
-            ResponseError, Setting, SettingVal, StartBootload,
+            decode_in_place, settings_from_raw, settings_to_vec, BootCommand, DataChunk,
+            Parameters, Request, RequestEnvelope, Response, ResponseEnvelope, ResponseError,
+            Setting, SettingVal, Slot, StartBootload, Status,
         },
         machine::{stm32g031_params, Bootable, Machine, Mode},
+        mock::{Layout, Op, RamFlash},
         CRC,
     };
-    use std::sync::{Arc, Mutex};
 
-    struct HwInner {
-        flash: Vec<u8>,
-        settings: Vec<u8>,
-    }
+    /// The STM32G031 layout
+    pub(crate) struct G031;
+
+    impl Layout for G031 {
+        const PARAMETERS: Parameters = stm32g031_params();
 
-    #[derive(Clone)]
-    struct AtomicHardware {
-        inner: Arc<Mutex<HwInner>>,
+        #[cfg(feature = "ed25519")]
+        const APP_PUBLIC_KEY: [u8; 32] = TEST_PUBLIC_KEY;
     }
 
-    impl AtomicHardware {
-        pub fn new() -> Self {
-            let params = Self::PARAMETERS;
-            assert_eq!(params.valid_flash_range.0, 0);
-            Self {
-                inner: Arc::new(Mutex::new(HwInner {
-                    flash: vec![0xA5u8; params.valid_flash_range.1 as usize],
-                    settings: vec![0xCCu8; 4usize + params.settings_max as usize],
-                })),
-            }
-        }
+    /// The STM32G031 layout, with the app range split into two slots
+    pub(crate) struct G031Ab;
+
+    impl Layout for G031Ab {
+        const PARAMETERS: Parameters = Parameters {
+            valid_app_range: (16 * 1024, 40 * 1024),
+            alt_app_range: Some((40 * 1024, 64 * 1024)),
+            ..stm32g031_params()
+        };
+
+        #[cfg(feature = "ed25519")]
+        const APP_PUBLIC_KEY: [u8; 32] = TEST_PUBLIC_KEY;
     }
 
-    impl Flash for AtomicHardware {
+    /// The STM32G031 layout, with its memory map so that vector tables
+    /// are checked
+    #[cfg(not(feature = "ed25519"))]
+    pub(crate) struct G031Mapped;
+
+    #[cfg(not(feature = "ed25519"))]
+    impl Layout for G031Mapped {
         const PARAMETERS: Parameters = stm32g031_params();
+        const MEMORY_MAP: Option<crate::vectors::MemoryMap> = Some(crate::vectors::MemoryMap {
+            flash_base: 0x0800_0000,
+            ram: (0x2000_0000, 0x2000_2000),
+        });
+    }
 
-        fn flash_range(&mut self, start: u32, data: &[u8]) {
-            assert_eq!(Self::PARAMETERS.valid_flash_range.0, 0);
-            let mut inner = self.inner.lock().unwrap();
-            let su = start as usize;
-            let range = inner.flash.get_mut(su..su + data.len()).unwrap();
+    /// In-memory hardware with the test keys
+    pub(crate) fn test_flash<L: Layout>() -> RamFlash<L> {
+        #[allow(unused_mut)]
+        let mut hw = RamFlash::new();
+        #[cfg(feature = "auth")]
+        hw.set_auth_key(TEST_AUTH_KEY);
+        #[cfg(feature = "encrypt")]
+        hw.set_encryption_key(TEST_ENCRYPTION_KEY);
+        hw
+    }
 
-            range
-                .iter()
-                .for_each(|b| assert_eq!(*b, 0xFF, "flash not erased!"));
-            range.copy_from_slice(data)
-        }
+    // The public half of `TEST_SECRET_KEY`
+    #[cfg(feature = "ed25519")]
+    const TEST_PUBLIC_KEY: [u8; 32] = [
+        0x21, 0x52, 0xf8, 0xd1, 0x9b, 0x79, 0x1d, 0x24, 0x45, 0x32, 0x42, 0xe1, 0x5f, 0x2e, 0xab,
+        0x6c, 0xb7, 0xcf, 0xfa, 0x7b, 0x6a, 0x5e, 0xd3, 0x00, 0x97, 0x96, 0x0e, 0x06, 0x98, 0x81,
+        0xdb, 0x12,
+    ];
+
+    #[cfg(feature = "ed25519")]
+    pub(crate) const TEST_SECRET_KEY: [u8; 32] = [0x42; 32];
+
+    #[cfg(feature = "auth")]
+    pub(crate) const TEST_AUTH_KEY: [u8; 32] = [0x11; 32];
+
+    #[cfg(feature = "encrypt")]
+    pub(crate) const TEST_ENCRYPTION_KEY: [u8; 32] = [0x33; 32];
+
+    /// The nonce to start bootloads with, if the machine expects encryption
+    #[cfg(feature = "encrypt")]
+    pub(crate) const TEST_NONCE: Option<[u8; 12]> = Some([0x07; 12]);
+    #[cfg(not(feature = "encrypt"))]
+    pub(crate) const TEST_NONCE: Option<[u8; 12]> = None;
+
+    /// A chunk of image data, owned so that requests can borrow it
+    pub(crate) struct Chunk {
+        data_addr: u32,
+        sub_crc32: u32,
+        data: Vec<u8>,
+    }
 
-        fn erase_range(&mut self, start: u32, len: u32) {
-            assert_eq!(Self::PARAMETERS.valid_flash_range.0, 0);
-            let mut inner = self.inner.lock().unwrap();
-            let su = start as usize;
-            let lu = len as usize;
-            inner
-                .flash
-                .get_mut(su..su + lu)
-                .unwrap()
-                .iter_mut()
-                .for_each(|b| *b = 0xFF);
+    impl Chunk {
+        pub(crate) fn req(&self) -> Request<'_> {
+            Request::DataChunk(DataChunk {
+                data_addr: self.data_addr,
+                sub_crc32: self.sub_crc32,
+                data: &self.data,
+            })
         }
+    }
 
-        fn write_settings(&mut self, data: &[u8]) {
-            let mut inner = self.inner.lock().unwrap();
-            inner
-                .settings
-                .get_mut(..data.len())
-                .unwrap()
-                .copy_from_slice(data);
-        }
+    /// A full chunk of `fill` bytes for a load starting at the beginning of
+    /// the app range, encrypted if the machine expects it
+    pub(crate) fn chunk(data_addr: u32, fill: u8) -> Chunk {
+        chunk_in(16 * 1024, data_addr, fill)
+    }
 
-        fn read_range(&mut self, start: u32, len: u32) -> &[u8] {
-            assert_eq!(Self::PARAMETERS.valid_flash_range.0, 0);
-            let inner = self.inner.lock().unwrap();
-            let su = start as usize;
-            let lu = len as usize;
-            let vec = inner.flash.get(su..su + lu).unwrap().to_vec();
+    /// A full chunk of `fill` bytes for a load starting at `start_addr`
+    #[cfg_attr(not(feature = "encrypt"), allow(unused_variables))]
+    fn chunk_in(start_addr: u32, data_addr: u32, fill: u8) -> Chunk {
+        #[allow(unused_mut)]
+        let mut data = vec![fill; 2048];
+        #[cfg(feature = "encrypt")]
+        crate::crypt::apply_keystream(
+            &TEST_ENCRYPTION_KEY,
+            &TEST_NONCE.unwrap(),
+            data_addr - start_addr,
+            &mut data,
+        );
+        Chunk {
+            data_addr,
+            sub_crc32: CRC.checksum(&[fill; 2048]),
+            data,
+        }
+    }
 
-            // This is: uh, not great.
-            vec.leak()
+    /// The response to a `chunk(..)` at the start of a load
+    fn ok_chunk(fill: u8) -> Response<'static> {
+        Response::ChunkAccepted {
+            data_addr: fill as u32 * 1024,
+            data_len: 2048,
+            crc32: CRC.checksum(&[fill; 2048]),
         }
+    }
+
+    /// Authenticate with the machine
+    #[cfg(feature = "auth")]
+    pub(crate) fn authenticate<HW: Flash>(machine: &mut Machine<HW>) {
+        let nonce = match machine.dispatch(Request::GetChallenge) {
+            Ok(Response::Challenge { nonce }) => nonce,
+            _ => panic!(),
+        };
+        let response = crate::auth::respond(&TEST_AUTH_KEY, &nonce);
+        assert_eq!(
+            machine.dispatch(Request::Authenticate { response }),
+            Ok(Response::Authenticated)
+        );
+    }
+
+    /// Authentication isn't required without the `auth` feature
+    #[cfg(not(feature = "auth"))]
+    pub(crate) fn authenticate<HW: Flash>(_machine: &mut Machine<HW>) {}
+
+    /// The settings needed to boot the given image
+    pub(crate) fn app_settings(image: &[u8]) -> Vec<u8> {
+        slot_settings(&[(Slot::A, image)])
+    }
 
-        fn boot(&mut self) -> ! {
-            todo!()
+    /// The settings needed to boot the given image in each slot
+    fn slot_settings(images: &[(Slot, &[u8])]) -> Vec<u8> {
+        let mut settings = Vec::new();
+        for (slot, image) in images {
+            settings.push(Setting {
+                name_ascii: slot.len_setting(),
+                val: SettingVal::U32(image.len() as u32),
+            });
+            settings.push(Setting {
+                name_ascii: slot.crc_setting(),
+                val: SettingVal::U32(CRC.checksum(image)),
+            });
         }
+        #[cfg(feature = "ed25519")]
+        let sigs: Vec<[u8; 64]> = images
+            .iter()
+            .map(|(_, image)| crate::sig::sign(&TEST_SECRET_KEY, image, 0))
+            .collect();
+        #[cfg(feature = "ed25519")]
+        for ((slot, _), sig) in images.iter().zip(&sigs) {
+            settings.push(Setting {
+                name_ascii: slot.sig_setting(),
+                val: SettingVal::ByteSlice(sig),
+            });
+        }
+        settings_to_vec(&settings)
+    }
 
-        fn read_settings_raw(&mut self) -> &[u8] {
-            let inner = self.inner.lock().unwrap();
-            // This is: uh, not great.
-            inner.settings.clone().leak()
+    /// Send each request to the machine, and check we get the expected response
+    fn run_sequence<HW: Flash>(
+        machine: &mut Machine<HW>,
+        seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)],
+    ) {
+        for (seq_no, (req, exp_res)) in seq.iter().enumerate() {
+            let mut buf = [0u8; 3072];
+            let enc_used = RequestEnvelope {
+                seq: seq_no as u32,
+                req: req.clone(),
+            }
+            .encode_to_vec();
+            buf[..enc_used.len()].copy_from_slice(&enc_used);
+            machine.process(&mut buf).unwrap();
+
+            // Did we get a response, and is it the expected response?
+            let act_res: ResponseEnvelope<'_> = decode_in_place(&mut buf).unwrap();
+            assert_eq!(act_res.seq, Some(seq_no as u32));
+            assert_eq!(&act_res.resp, exp_res);
         }
     }
 
     #[test]
     fn do_a_bootload() {
-        // Create a fake (in-memory) hardware impl
-        let hw = AtomicHardware::new();
+        // Create a fake (in-memory) hardware impl, filled with a pattern to
+        // show what gets erased
+        let mut hw = test_flash::<G031>();
+        hw.flash_mut().fill(0xA5);
 
         // Create the bootload "server": this usually runs on-device
-        let mut machine = Machine::new(hw.clone());
+        let mut machine = Machine::new(hw);
+        authenticate(&mut machine);
 
-        let mut digest = CRC.digest();
-        digest.update(&[16; 2048]);
-        digest.update(&[18; 2048]);
-        digest.update(&[20; 2048]);
-        digest.update(&[22; 2048]);
-        let ttl_crc = digest.finalize();
+        let mut image = Vec::new();
+        image.extend_from_slice(&[16; 2048]);
+        image.extend_from_slice(&[18; 2048]);
+        image.extend_from_slice(&[20; 2048]);
+        image.extend_from_slice(&[22; 2048]);
+        let ttl_crc = CRC.checksum(&image);
 
-        let settings = settings_to_vec(&[
-            Setting {
-                name_ascii: b"app_len",
-                val: SettingVal::U32(8 * 1024),
-            },
-            Setting {
-                name_ascii: b"app_crc",
-                val: SettingVal::U32(ttl_crc),
-            },
-        ]);
+        let settings = app_settings(&image);
+        let chunks = [16, 18, 20, 22].map(|fill| chunk(fill as u32 * 1024, fill));
 
         // The sequence of commands sent and expected responses
         let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
@@ -722,15 +1614,14 @@ pub mod test {
                     start_addr: 16 * 1024,
                     length: 8 * 1024,
                     crc32: ttl_crc,
+                    nonce: TEST_NONCE,
+                    app_version: 0,
+                    allow_rollback: false,
                 }),
                 Ok(Response::BootloadStarted),
             ),
             (
-                Request::DataChunk(DataChunk {
-                    data_addr: 16 * 1024,
-                    sub_crc32: CRC.checksum(&[16; 2048]),
-                    data: &[16; 2048],
-                }),
+                chunks[0].req(),
                 Ok(Response::ChunkAccepted {
                     data_addr: 16 * 1024,
                     data_len: 2048,
@@ -738,11 +1629,7 @@ pub mod test {
                 }),
             ),
             (
-                Request::DataChunk(DataChunk {
-                    data_addr: 18 * 1024,
-                    sub_crc32: CRC.checksum(&[18; 2048]),
-                    data: &[18; 2048],
-                }),
+                chunks[1].req(),
                 Ok(Response::ChunkAccepted {
                     data_addr: 18 * 1024,
                     data_len: 2048,
@@ -750,11 +1637,7 @@ pub mod test {
                 }),
             ),
             (
-                Request::DataChunk(DataChunk {
-                    data_addr: 20 * 1024,
-                    sub_crc32: CRC.checksum(&[20; 2048]),
-                    data: &[20; 2048],
-                }),
+                chunks[2].req(),
                 Ok(Response::ChunkAccepted {
                     data_addr: 20 * 1024,
                     data_len: 2048,
@@ -762,11 +1645,7 @@ pub mod test {
                 }),
             ),
             (
-                Request::DataChunk(DataChunk {
-                    data_addr: 22 * 1024,
-                    sub_crc32: CRC.checksum(&[22; 2048]),
-                    data: &[22; 2048],
-                }),
+                chunks[3].req(),
                 Ok(Response::ChunkAccepted {
                     data_addr: 22 * 1024,
                     data_len: 2048,
@@ -791,21 +1670,11 @@ pub mod test {
             ),
         ];
 
-        for (req, exp_res) in seq {
-            let mut buf = [0u8; 3072];
-            let enc_used = req.encode_to_vec();
-            buf[..enc_used.len()].copy_from_slice(&enc_used);
-            machine.process(&mut buf).unwrap();
-
-            // Did we get a response, and is it the expected response?
-            let act_res: Result<Response<'_>, ResponseError> = decode_in_place(&mut buf).unwrap();
-            assert_eq!(&act_res, exp_res);
-        }
+        run_sequence(&mut machine, seq);
 
         // Memory test!
         {
-            let hwinner = hw.inner.lock().unwrap();
-            let flash = &hwinner.flash;
+            let flash = machine.hardware().flash();
 
             // Unprogrammed regions
             assert_eq!(&flash[..16 * 1024], [0xA5; 16 * 1024].as_slice());
@@ -821,4 +1690,1269 @@ pub mod test {
         // We commanded NO reboot after flashing
         assert!(matches!(machine.mode, Mode::Idle));
     }
+
+    #[cfg(not(feature = "no-read-range"))]
+    #[test]
+    fn read_policy() {
+        use super::ReadPolicy;
+
+        let mut machine = Machine::new(test_flash::<G031>());
+        authenticate(&mut machine);
+        let read = |machine: &mut Machine<RamFlash<G031>>, start_addr| {
+            machine.dispatch(Request::ReadRange {
+                start_addr,
+                len: 16,
+            })
+        };
+
+        // Decrypted images stay on the device, unless reads are allowed
+        #[cfg(feature = "encrypt")]
+        {
+            assert_eq!(
+                read(&mut machine, 16 * 1024),
+                Err(ResponseError::ReadDenied)
+            );
+            machine.set_read_policy(ReadPolicy::Everything);
+        }
+
+        // The bootloader itself
+        assert!(matches!(
+            read(&mut machine, 0),
+            Ok(Response::ReadRange { .. })
+        ));
+        assert!(matches!(
+            read(&mut machine, 16 * 1024),
+            Ok(Response::ReadRange { .. })
+        ));
+
+        // The test hardware keeps its settings outside of flash
+        machine.set_read_policy(ReadPolicy::AppAndSettings);
+        assert_eq!(read(&mut machine, 0), Err(ResponseError::ReadDenied));
+        assert!(matches!(
+            read(&mut machine, 16 * 1024),
+            Ok(Response::ReadRange { .. })
+        ));
+
+        machine.set_read_policy(ReadPolicy::AppOnly);
+        assert_eq!(read(&mut machine, 0), Err(ResponseError::ReadDenied));
+        assert_eq!(
+            read(&mut machine, (16 * 1024) - 8),
+            Err(ResponseError::ReadDenied)
+        );
+        assert!(matches!(
+            read(&mut machine, 16 * 1024),
+            Ok(Response::ReadRange { .. })
+        ));
+        assert_eq!(
+            read(&mut machine, 64 * 1024),
+            Err(ResponseError::BadRangeEnd)
+        );
+        assert_eq!(
+            machine.dispatch(Request::ReadRange {
+                start_addr: 16 * 1024,
+                len: 4096,
+            }),
+            Err(ResponseError::BadRangeLength {
+                actual: 4096,
+                max: 2048,
+            })
+        );
+        machine.set_read_policy(ReadPolicy::Nothing);
+        assert_eq!(
+            read(&mut machine, 16 * 1024),
+            Err(ResponseError::ReadDenied)
+        );
+    }
+
+    #[test]
+    fn retransmitted_chunk() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        authenticate(&mut machine);
+
+        let first = chunk(16 * 1024, 16);
+        let other = chunk(16 * 1024, 17);
+        let accepted = || {
+            Ok(Response::ChunkAccepted {
+                data_addr: 16 * 1024,
+                data_len: 2048,
+                crc32: CRC.checksum(&[16; 2048]),
+            })
+        };
+
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            (
+                Request::StartBootload(StartBootload {
+                    start_addr: 16 * 1024,
+                    length: 4 * 1024,
+                    crc32: 0,
+                    nonce: TEST_NONCE,
+                    app_version: 0,
+                    allow_rollback: false,
+                }),
+                Ok(Response::BootloadStarted),
+            ),
+            (first.req(), accepted()),
+            // Host didn't hear back, and tries again
+            (first.req(), accepted()),
+            // A *different* chunk at the same address is still out of order
+            (
+                other.req(),
+                Err(ResponseError::SkippedRange {
+                    expected: 18 * 1024,
+                    actual: 16 * 1024,
+                }),
+            ),
+        ];
+        run_sequence(&mut machine, seq);
+
+        // Only the first chunk was written, and only once
+        match &machine.mode {
+            Mode::BootLoad(meta) => assert_eq!(meta.addr_current, 18 * 1024),
+            _ => panic!(),
+        }
+        let flash = machine.hardware().flash();
+        assert_eq!(&flash[16 * 1024..][..2048], [16; 2048].as_slice());
+        assert_eq!(&flash[18 * 1024..][..2048], [0xFF; 2048].as_slice());
+    }
+
+    #[test]
+    fn boot_requests() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        authenticate(&mut machine);
+        let start = StartBootload {
+            start_addr: 16 * 1024,
+            length: 4 * 1024,
+            crc32: 0,
+            nonce: TEST_NONCE,
+            app_version: 0,
+            allow_rollback: false,
+        };
+
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            // Nothing to boot, so stay put
+            (
+                Request::Boot(BootCommand::BootIfBootable),
+                Ok(Response::ConfirmBootCmd {
+                    will_boot: false,
+                    boot_status: Bootable::NoMissingSettings,
+                }),
+            ),
+            (Request::GetStatus, Ok(Response::Status(Status::Idle))),
+            // Don't abandon a load unless forced to
+            (
+                Request::StartBootload(start.clone()),
+                Ok(Response::BootloadStarted),
+            ),
+            (
+                Request::Boot(BootCommand::BootIfBootable),
+                Err(ResponseError::BootloadInProgress),
+            ),
+            (
+                Request::GetStatus,
+                Ok(Response::Status(Status::Started {
+                    start_addr: 16 * 1024,
+                    length: 4 * 1024,
+                    crc32: 0,
+                })),
+            ),
+        ];
+        run_sequence(&mut machine, seq);
+
+        // Forced, so the load is abandoned. With `ed25519`, only ever for a
+        // signed image, and there isn't one.
+        let forced = !cfg!(feature = "ed25519");
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            (
+                Request::Boot(BootCommand::ForceBoot),
+                Ok(Response::ConfirmBootCmd {
+                    will_boot: forced,
+                    boot_status: Bootable::NoMissingSettings,
+                }),
+            ),
+            (
+                Request::GetStatus,
+                Ok(Response::Status(if forced {
+                    Status::BootPending
+                } else {
+                    Status::Started {
+                        start_addr: 16 * 1024,
+                        length: 4 * 1024,
+                        crc32: 0,
+                    }
+                })),
+            ),
+        ];
+        run_sequence(&mut machine, seq);
+    }
+
+    #[test]
+    fn version_rollback() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        authenticate(&mut machine);
+        machine.hardware_mut().set_min_app_version(3);
+
+        let mut image = Vec::new();
+        image.extend_from_slice(&[16; 2048]);
+        image.extend_from_slice(&[18; 2048]);
+        let versioned = |version| {
+            #[cfg(feature = "ed25519")]
+            let sig = crate::sig::sign(&TEST_SECRET_KEY, &image, version);
+            settings_to_vec(&[
+                Setting {
+                    name_ascii: b"app_len",
+                    val: SettingVal::U32(image.len() as u32),
+                },
+                Setting {
+                    name_ascii: b"app_crc",
+                    val: SettingVal::U32(CRC.checksum(&image)),
+                },
+                Setting {
+                    name_ascii: super::VERSION_SETTING,
+                    val: SettingVal::U32(version),
+                },
+                #[cfg(feature = "ed25519")]
+                Setting {
+                    name_ascii: crate::sig::SIG_SETTING,
+                    val: SettingVal::ByteSlice(&sig),
+                },
+            ])
+        };
+        let start = |app_version, allow_rollback| {
+            Request::StartBootload(StartBootload {
+                start_addr: 16 * 1024,
+                length: 4 * 1024,
+                crc32: CRC.checksum(&image),
+                nonce: TEST_NONCE,
+                app_version,
+                allow_rollback,
+            })
+        };
+        let first = chunk(16 * 1024, 16);
+        let second = chunk(18 * 1024, 18);
+        let old_settings = versioned(2);
+        let new_settings = versioned(4);
+        let complete = || Request::CompleteBootload { boot: None };
+        let loaded = || {
+            Ok(Response::ConfirmComplete {
+                will_boot: false,
+                boot_status: Bootable::Yes {
+                    crc32: CRC.checksum(&image),
+                    length: 4 * 1024,
+                },
+            })
+        };
+        let rollback = || {
+            Err(ResponseError::VersionRollback {
+                current: 3,
+                attempted: 2,
+            })
+        };
+
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            (start(2, false), rollback()),
+            // Claiming to be new, but actually old
+            (start(4, false), Ok(Response::BootloadStarted)),
+            (first.req(), Ok(ok_chunk(16))),
+            (second.req(), Ok(ok_chunk(18))),
+            (
+                Request::WriteSettings {
+                    data: &old_settings,
+                },
+                Ok(Response::SettingsAccepted {
+                    data_len: old_settings.len() as u32,
+                }),
+            ),
+            (complete(), rollback()),
+        ];
+        run_sequence(&mut machine, seq);
+        assert_eq!(machine.hardware_mut().min_app_version(), 3);
+
+        // Authenticated hosts may roll back, but the minimum stays put. Each
+        // load needs a session of its own.
+        #[cfg(feature = "auth")]
+        {
+            authenticate(&mut machine);
+            let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+                (start(2, true), Ok(Response::BootloadStarted)),
+                (first.req(), Ok(ok_chunk(16))),
+                (second.req(), Ok(ok_chunk(18))),
+                (complete(), loaded()),
+            ];
+            run_sequence(&mut machine, seq);
+            assert_eq!(machine.hardware_mut().min_app_version(), 3);
+        }
+        #[cfg(not(feature = "auth"))]
+        run_sequence(&mut machine, &[(start(2, true), rollback())]);
+
+        // Loading a newer version raises the minimum
+        authenticate(&mut machine);
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            (start(4, false), Ok(Response::BootloadStarted)),
+            (first.req(), Ok(ok_chunk(16))),
+            (second.req(), Ok(ok_chunk(18))),
+            (
+                Request::WriteSettings {
+                    data: &new_settings,
+                },
+                Ok(Response::SettingsAccepted {
+                    data_len: new_settings.len() as u32,
+                }),
+            ),
+            (complete(), loaded()),
+        ];
+        run_sequence(&mut machine, seq);
+        assert_eq!(machine.hardware_mut().min_app_version(), 4);
+    }
+
+    #[cfg(not(feature = "ed25519"))]
+    #[test]
+    fn image_header() {
+        use crate::image::{with_header, ImageHeader, HEADER_LEN};
+
+        let mut hw = test_flash::<G031>();
+        let image = with_header(
+            &[0x5A; 4000],
+            ImageHeader {
+                image_len: 0,
+                image_crc: 0,
+                app_version: 1,
+                load_addr: 0x0800_4000 + HEADER_LEN as u32,
+                entry_point: 0x0800_4000 + HEADER_LEN as u32 + 0x101,
+                build_id: [0; 20],
+            },
+        );
+        hw.erase_range(16 * 1024, 8192);
+        hw.flash_range(16 * 1024, &image);
+
+        // The settings page is blank, but the image describes itself
+        assert_eq!(
+            hw.is_bootable(),
+            Bootable::Yes {
+                crc32: CRC.checksum(&[0x5A; 4000]),
+                length: HEADER_LEN + 4000,
+            }
+        );
+        assert_eq!(hw.app_vectors(), (16 * 1024) + HEADER_LEN as u32);
+
+        // Damage after the header is still caught
+        hw.flash_mut()[(16 * 1024) + HEADER_LEN + 100] = 0;
+        assert_eq!(hw.is_bootable(), Bootable::NoInvalidCrc);
+
+        // No header at all
+        hw.erase_range(16 * 1024, 8192);
+        assert_eq!(hw.is_bootable(), Bootable::NoMissingSettings);
+        assert_eq!(hw.app_vectors(), 16 * 1024);
+    }
+
+    #[cfg(not(feature = "ed25519"))]
+    #[test]
+    fn header_addresses() {
+        use crate::image::{with_header, ImageHeader, HEADER_LEN};
+
+        // Linked to run from just after the header
+        let vectors = 0x0800_4000 + HEADER_LEN as u32;
+        let reset = vectors + 0x101;
+        let mut app = vec![0x5A; 4096];
+        app[..4].copy_from_slice(&0x2000_2000u32.to_le_bytes());
+        app[4..8].copy_from_slice(&reset.to_le_bytes());
+        let good = Bootable::Yes {
+            crc32: CRC.checksum(&app),
+            length: HEADER_LEN + 4096,
+        };
+
+        let cases = [
+            (0x2000_2000u32, vectors, reset, good),
+            // Linked to run from the start of the slot, where the header is
+            (0x2000_2000, 0x0800_4000, reset, Bootable::NoInvalidSettings),
+            // Doesn't start where the vector table says
+            (
+                0x2000_2000,
+                vectors,
+                vectors + 0x201,
+                Bootable::NoInvalidSettings,
+            ),
+            // The vector table after the header is checked
+            (0xFFFF_FFFF, vectors, reset, Bootable::NoInvalidVectorTable),
+        ];
+        for (sp, load_addr, entry_point, expected) in cases {
+            app[..4].copy_from_slice(&sp.to_le_bytes());
+            let mut hw = test_flash::<G031Mapped>();
+            let image = with_header(
+                &app,
+                ImageHeader {
+                    image_len: 0,
+                    image_crc: 0,
+                    app_version: 1,
+                    load_addr,
+                    entry_point,
+                    build_id: [0; 20],
+                },
+            );
+            hw.erase_range(16 * 1024, 8192);
+            hw.flash_range(16 * 1024, &image);
+            assert_eq!(hw.is_bootable(), expected);
+        }
+    }
+
+    #[test]
+    fn ab_slots() {
+        let mut machine = Machine::new(test_flash::<G031Ab>());
+        authenticate(&mut machine);
+        let first = [0x11u8; 4096];
+        let second = [0x22u8; 4096];
+
+        // Load an image into the inactive slot, without completing the load
+        let load = |machine: &mut Machine<RamFlash<G031Ab>>, image: &[u8; 4096]| {
+            authenticate(machine);
+            let slot = match machine.dispatch(Request::GetSlots) {
+                Ok(Response::Slots { load, .. }) => load,
+                _ => panic!(),
+            };
+            let (start, _) = slot.range(&G031Ab::PARAMETERS).unwrap();
+            let sb = StartBootload {
+                start_addr: start,
+                length: 4096,
+                crc32: CRC.checksum(image),
+                nonce: TEST_NONCE,
+                app_version: 0,
+                allow_rollback: false,
+            };
+            assert_eq!(
+                machine.dispatch(Request::StartBootload(sb)),
+                Ok(Response::BootloadStarted)
+            );
+            for addr in [start, start + 2048] {
+                let resp = machine.dispatch(chunk_in(start, addr, image[0]).req());
+                assert!(matches!(resp, Ok(Response::ChunkAccepted { .. })));
+            }
+            slot
+        };
+        let complete = |machine: &mut Machine<RamFlash<G031Ab>>, settings: &[u8]| {
+            let resp = machine.dispatch(Request::WriteSettings { data: settings });
+            assert!(matches!(resp, Ok(Response::SettingsAccepted { .. })));
+            machine.dispatch(Request::CompleteBootload { boot: None })
+        };
+        let yes = |image: &[u8]| Bootable::Yes {
+            crc32: CRC.checksum(image),
+            length: 4096,
+        };
+
+        // The first load goes into slot B, which only becomes active once complete
+        assert_eq!(load(&mut machine, &first), Slot::B);
+        assert_eq!(machine.hardware_mut().active_slot(), Slot::A);
+        assert_eq!(
+            complete(&mut machine, &slot_settings(&[(Slot::B, &first)])),
+            Ok(Response::ConfirmComplete {
+                will_boot: false,
+                boot_status: yes(&first),
+            })
+        );
+        assert_eq!(
+            machine.dispatch(Request::GetSlots),
+            Ok(Response::Slots {
+                active: Slot::B,
+                load: Slot::A,
+            })
+        );
+
+        // An interrupted load leaves the active application alone
+        assert_eq!(load(&mut machine, &second), Slot::A);
+        assert_eq!(
+            machine.dispatch(Request::AbortBootload),
+            Ok(Response::BootloadAborted)
+        );
+        assert_eq!(
+            machine.dispatch(Request::IsBootable),
+            Ok(Response::BootableStatus(yes(&first)))
+        );
+
+        // A complete one switches over
+        assert_eq!(load(&mut machine, &second), Slot::A);
+        let both = slot_settings(&[(Slot::A, &second), (Slot::B, &first)]);
+        assert_eq!(
+            complete(&mut machine, &both),
+            Ok(Response::ConfirmComplete {
+                will_boot: false,
+                boot_status: yes(&second),
+            })
+        );
+        assert_eq!(machine.hardware_mut().active_slot(), Slot::A);
+
+        // If the new application is damaged, the previous one is booted
+        // instead, but asking doesn't change anything
+        machine.hardware_mut().flash_mut()[(16 * 1024) + 100] = 0;
+        assert_eq!(
+            machine.dispatch(Request::IsBootable),
+            Ok(Response::BootableStatus(yes(&first)))
+        );
+        assert_eq!(machine.hardware_mut().active_slot(), Slot::A);
+
+        // The next load replaces the damaged one, not the one to be booted
+        assert_eq!(
+            machine.dispatch(Request::GetSlots),
+            Ok(Response::Slots {
+                active: Slot::A,
+                load: Slot::A,
+            })
+        );
+
+        // Booting falls back
+        machine.prepare_boot();
+        assert_eq!(machine.hardware_mut().active_slot(), Slot::B);
+    }
+
+    #[test]
+    fn trial_boot() {
+        let mut machine = Machine::new(test_flash::<G031Ab>());
+        let old = [0x11u8; 4096];
+        let new = [0x22u8; 4096];
+        machine.hardware_mut().erase_range(16 * 1024, 4096);
+        machine.hardware_mut().flash_range(16 * 1024, &old);
+        machine.hardware_mut().erase_range(40 * 1024, 4096);
+        machine.hardware_mut().flash_range(40 * 1024, &new);
+        let yes = |image: &[u8]| Bootable::Yes {
+            crc32: CRC.checksum(image),
+            length: 4096,
+        };
+
+        // `new` was just loaded into slot B, and gets two tries
+        let base = slot_settings(&[(Slot::A, &old), (Slot::B, &new)]);
+        let mut settings: Vec<Setting<'_>> = settings_from_raw(&base).unwrap().collect();
+        settings.push(Setting {
+            name_ascii: Slot::B.trial_setting(),
+            val: SettingVal::U32(2),
+        });
+        let on_trial = settings_to_vec(&settings);
+        machine.hardware_mut().write_settings(&on_trial);
+        machine.hardware_mut().set_active_slot(Slot::B);
+
+        assert_eq!(machine.hardware_mut().is_bootable(), yes(&new));
+        machine.prepare_boot();
+        assert_eq!(machine.hardware_mut().is_bootable(), yes(&new));
+        machine.prepare_boot();
+
+        // It never confirmed itself, so go back to the previous application,
+        // which isn't on trial
+        assert_eq!(machine.hardware_mut().is_bootable(), yes(&old));
+        assert_eq!(machine.hardware_mut().active_slot(), Slot::B);
+        machine.prepare_boot();
+        assert_eq!(machine.hardware_mut().active_slot(), Slot::A);
+        assert_eq!(machine.hardware_mut().boot_attempts(), 0);
+
+        // Had it confirmed itself, it would have stayed
+        machine.hardware_mut().set_active_slot(Slot::B);
+        machine.hardware_mut().set_boot_attempts(1);
+        let mut buf = [0u8; 512];
+        let confirmed = crate::app::confirm_boot(&on_trial, &mut buf)
+            .unwrap()
+            .unwrap();
+        machine.hardware_mut().write_settings(confirmed);
+        machine.count_boot_attempt();
+        machine.count_boot_attempt();
+        assert_eq!(machine.hardware_mut().is_bootable(), yes(&new));
+        assert_eq!(
+            crate::app::confirm_boot(confirmed, &mut [0u8; 512]),
+            Ok(None)
+        );
+
+        // With nothing to go back to, stay in the bootloader
+        let mut machine = Machine::new(test_flash::<G031>());
+        machine.hardware_mut().erase_range(16 * 1024, 4096);
+        machine.hardware_mut().flash_range(16 * 1024, &old);
+        let base = app_settings(&old);
+        let mut settings: Vec<Setting<'_>> = settings_from_raw(&base).unwrap().collect();
+        settings.push(Setting {
+            name_ascii: Slot::A.trial_setting(),
+            val: SettingVal::U32(1),
+        });
+        machine
+            .hardware_mut()
+            .write_settings(&settings_to_vec(&settings));
+        assert_eq!(machine.hardware_mut().is_bootable(), yes(&old));
+        machine.count_boot_attempt();
+        assert_eq!(
+            machine.hardware_mut().is_bootable(),
+            Bootable::NoFailedTrial
+        );
+    }
+
+    #[test]
+    fn flash_faults() {
+        use crate::mock::Fault;
+
+        let old = [0xA5u8; 8192];
+        let mut image = Vec::new();
+        for fill in [16, 18, 20, 22] {
+            image.extend_from_slice(&[fill; 2048]);
+        }
+        let settings = app_settings(&image);
+
+        // Load `image` over a bootable `old`, and see what the machine thinks
+        let load = |fault: Option<Fault>| {
+            let mut hw = test_flash::<G031>();
+            hw.erase_range(16 * 1024, 8192);
+            hw.flash_range(16 * 1024, &old);
+            hw.write_settings(&app_settings(&old));
+            if let Some(fault) = fault {
+                hw.inject(fault);
+            }
+            let mut machine = Machine::new(hw);
+            authenticate(&mut machine);
+
+            let _ = machine.dispatch(Request::StartBootload(StartBootload {
+                start_addr: 16 * 1024,
+                length: 8 * 1024,
+                crc32: CRC.checksum(&image),
+                nonce: TEST_NONCE,
+                app_version: 0,
+                allow_rollback: false,
+            }));
+            for fill in [16, 18, 20, 22] {
+                let _ = machine.dispatch(chunk(fill as u32 * 1024, fill).req());
+            }
+            let _ = machine.dispatch(Request::WriteSettings { data: &settings });
+            let reported = match machine.dispatch(Request::CompleteBootload { boot: None }) {
+                Ok(Response::ConfirmComplete { boot_status, .. }) => Some(boot_status),
+                _ => None,
+            };
+
+            // And after a reset
+            let mut hw = core::mem::take(machine.hardware_mut());
+            hw.power_on();
+            let after_reset = Machine::new(hw).is_bootable();
+            (reported, after_reset)
+        };
+        let yes = || Bootable::Yes {
+            crc32: CRC.checksum(&image),
+            length: 8 * 1024,
+        };
+
+        let (reported, after_reset) = load(None);
+        assert_eq!(reported, Some(yes()));
+        assert_eq!(after_reset, yes());
+
+        let faults = [
+            Fault::Erase { addr: 18 * 1024 },
+            Fault::Program {
+                addr: (20 * 1024) + 100,
+            },
+            Fault::BitFlip {
+                addr: (22 * 1024) + 7,
+                bit: 3,
+            },
+            Fault::PowerLoss { addr: 19 * 1024 },
+            Fault::PowerLoss {
+                addr: (21 * 1024) + 3,
+            },
+        ];
+        for fault in faults {
+            let (reported, after_reset) = load(Some(fault.clone()));
+            assert!(
+                !matches!(reported, Some(Bootable::Yes { .. })),
+                "{:?}: {:?}",
+                fault,
+                reported
+            );
+            assert!(
+                !matches!(after_reset, Bootable::Yes { .. }),
+                "{:?}: {:?}",
+                fault,
+                after_reset
+            );
+        }
+    }
+
+    #[test]
+    fn boot_window() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        assert_eq!(machine.boot_window_ms(), None);
+
+        let window = [Setting {
+            name_ascii: super::BOOT_WINDOW_SETTING,
+            val: SettingVal::U32(1500),
+        }];
+        machine
+            .hardware_mut()
+            .write_settings(&settings_to_vec(&window));
+        assert_eq!(machine.boot_window_ms(), Some(1500));
+
+        // Line noise doesn't count as a host showing up
+        assert!(!machine.received_request());
+        let mut noise = [0x55u8, 0x12, 0x00];
+        machine.process(&mut noise);
+        assert!(!machine.received_request());
+
+        let ping = RequestEnvelope {
+            seq: 1,
+            req: Request::Ping(3),
+        }
+        .encode_to_vec();
+        let mut buf = [0u8; 64];
+        buf[..ping.len()].copy_from_slice(&ping);
+        assert!(machine.process(&mut buf).is_some());
+        assert!(machine.received_request());
+    }
+
+    #[test]
+    fn encryption() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        authenticate(&mut machine);
+
+        // The host and device have to agree on whether images are encrypted
+        let mismatched = Request::StartBootload(StartBootload {
+            start_addr: 16 * 1024,
+            length: 4 * 1024,
+            crc32: 0,
+            nonce: match TEST_NONCE {
+                Some(_) => None,
+                None => Some([0x07; 12]),
+            },
+            app_version: 0,
+            allow_rollback: false,
+        });
+        let expected = if cfg!(feature = "encrypt") {
+            ResponseError::EncryptionRequired
+        } else {
+            ResponseError::EncryptionNotSupported
+        };
+        assert_eq!(machine.dispatch(mismatched), Err(expected));
+        assert!(matches!(machine.mode, Mode::Idle));
+
+        // A chunk encrypted with the wrong key decrypts to garbage, which
+        // is caught before anything is written
+        #[cfg(feature = "encrypt")]
+        {
+            let start = Request::StartBootload(StartBootload {
+                start_addr: 16 * 1024,
+                length: 4 * 1024,
+                crc32: 0,
+                nonce: TEST_NONCE,
+                app_version: 0,
+                allow_rollback: false,
+            });
+            assert_eq!(machine.dispatch(start), Ok(Response::BootloadStarted));
+
+            let mut data = [16; 2048];
+            crate::crypt::apply_keystream(&[0x44; 32], &TEST_NONCE.unwrap(), 0, &mut data);
+            let resp = machine.dispatch(Request::DataChunk(DataChunk {
+                data_addr: 16 * 1024,
+                sub_crc32: CRC.checksum(&[16; 2048]),
+                data: &data,
+            }));
+            assert!(matches!(resp, Err(ResponseError::BadSubCrc { .. })));
+            let flash = machine.hardware().flash();
+            assert_eq!(&flash[16 * 1024..][..2048], [0xFF; 2048].as_slice());
+        }
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn signed_images() {
+        let mut hw = test_flash::<G031>();
+        let image = [0x5Au8; 4096];
+        hw.erase_range(16 * 1024, 4096);
+        hw.flash_range(16 * 1024, &image);
+
+        // Properly signed
+        hw.write_settings(&app_settings(&image));
+        assert_eq!(
+            hw.is_bootable(),
+            Bootable::Yes {
+                crc32: CRC.checksum(&image),
+                length: 4096
+            }
+        );
+
+        // No signature at all
+        let unsigned = settings_to_vec(&[
+            Setting {
+                name_ascii: b"app_len",
+                val: SettingVal::U32(4096),
+            },
+            Setting {
+                name_ascii: b"app_crc",
+                val: SettingVal::U32(CRC.checksum(&image)),
+            },
+        ]);
+        hw.write_settings(&unsigned);
+        assert_eq!(hw.is_bootable(), Bootable::NoMissingSettings);
+
+        // Signed by someone else. The CRC is still fine, which is all that
+        // an attacker would need without signatures.
+        let sig = crate::sig::sign(&[0x24; 32], &image, 0);
+        let forged = settings_to_vec(&[
+            Setting {
+                name_ascii: b"app_len",
+                val: SettingVal::U32(4096),
+            },
+            Setting {
+                name_ascii: b"app_crc",
+                val: SettingVal::U32(CRC.checksum(&image)),
+            },
+            Setting {
+                name_ascii: crate::sig::SIG_SETTING,
+                val: SettingVal::ByteSlice(&sig),
+            },
+        ]);
+        hw.write_settings(&forged);
+        assert_eq!(hw.is_bootable(), Bootable::NoBadSignature);
+
+        // Properly signed, but claiming to be a newer version than it was
+        // signed as
+        let sig = crate::sig::sign(&TEST_SECRET_KEY, &image, 0);
+        let bumped = settings_to_vec(&[
+            Setting {
+                name_ascii: b"app_len",
+                val: SettingVal::U32(4096),
+            },
+            Setting {
+                name_ascii: b"app_crc",
+                val: SettingVal::U32(CRC.checksum(&image)),
+            },
+            Setting {
+                name_ascii: super::VERSION_SETTING,
+                val: SettingVal::U32(5),
+            },
+            Setting {
+                name_ascii: crate::sig::SIG_SETTING,
+                val: SettingVal::ByteSlice(&sig),
+            },
+        ]);
+        hw.write_settings(&bumped);
+        assert_eq!(hw.is_bootable(), Bootable::NoBadSignature);
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn force_boot_needs_a_signature() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        authenticate(&mut machine);
+
+        let mut image = Vec::new();
+        image.extend_from_slice(&[16; 2048]);
+        image.extend_from_slice(&[18; 2048]);
+        let unsigned = settings_to_vec(&[
+            Setting {
+                name_ascii: b"app_len",
+                val: SettingVal::U32(4096),
+            },
+            Setting {
+                name_ascii: b"app_crc",
+                val: SettingVal::U32(CRC.checksum(&image)),
+            },
+        ]);
+        let first = chunk(16 * 1024, 16);
+        let second = chunk(18 * 1024, 18);
+
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            (
+                Request::StartBootload(StartBootload {
+                    start_addr: 16 * 1024,
+                    length: 4 * 1024,
+                    crc32: CRC.checksum(&image),
+                    nonce: TEST_NONCE,
+                    app_version: 0,
+                    allow_rollback: false,
+                }),
+                Ok(Response::BootloadStarted),
+            ),
+            (first.req(), Ok(ok_chunk(16))),
+            (second.req(), Ok(ok_chunk(18))),
+            (
+                Request::WriteSettings { data: &unsigned },
+                Ok(Response::SettingsAccepted {
+                    data_len: unsigned.len() as u32,
+                }),
+            ),
+            (
+                Request::CompleteBootload {
+                    boot: Some(BootCommand::ForceBoot),
+                },
+                Ok(Response::ConfirmComplete {
+                    will_boot: false,
+                    boot_status: Bootable::NoMissingSettings,
+                }),
+            ),
+        ];
+        run_sequence(&mut machine, seq);
+
+        authenticate(&mut machine);
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            (
+                Request::Boot(BootCommand::ForceBoot),
+                Ok(Response::ConfirmBootCmd {
+                    will_boot: false,
+                    boot_status: Bootable::NoMissingSettings,
+                }),
+            ),
+            (Request::GetStatus, Ok(Response::Status(Status::Idle))),
+        ];
+        run_sequence(&mut machine, seq);
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn sessions_end() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        let mut image = Vec::new();
+        image.extend_from_slice(&[16; 2048]);
+        image.extend_from_slice(&[18; 2048]);
+        let start = Request::StartBootload(StartBootload {
+            start_addr: 16 * 1024,
+            length: 4 * 1024,
+            crc32: CRC.checksum(&image),
+            nonce: TEST_NONCE,
+            app_version: 0,
+            allow_rollback: false,
+        });
+        let settings = app_settings(&image);
+        let first = chunk(16 * 1024, 16);
+        let second = chunk(18 * 1024, 18);
+
+        // Aborting a load
+        authenticate(&mut machine);
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            (start.clone(), Ok(Response::BootloadStarted)),
+            (Request::AbortBootload, Ok(Response::BootloadAborted)),
+            (start.clone(), Err(ResponseError::Unauthorized)),
+        ];
+        run_sequence(&mut machine, seq);
+
+        // Completing one
+        authenticate(&mut machine);
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            (start.clone(), Ok(Response::BootloadStarted)),
+            (first.req(), Ok(ok_chunk(16))),
+            (second.req(), Ok(ok_chunk(18))),
+            (
+                Request::WriteSettings { data: &settings },
+                Ok(Response::SettingsAccepted {
+                    data_len: settings.len() as u32,
+                }),
+            ),
+            (
+                Request::CompleteBootload { boot: None },
+                Ok(Response::ConfirmComplete {
+                    will_boot: false,
+                    boot_status: Bootable::Yes {
+                        crc32: CRC.checksum(&image),
+                        length: 4 * 1024,
+                    },
+                }),
+            ),
+            (Request::GetSettings, Err(ResponseError::Unauthorized)),
+        ];
+        run_sequence(&mut machine, seq);
+
+        // The host going away
+        authenticate(&mut machine);
+        assert!(machine.dispatch(Request::GetSettings).is_ok());
+        machine.end_session();
+        assert_eq!(
+            machine.dispatch(Request::GetSettings),
+            Err(ResponseError::Unauthorized)
+        );
+    }
+
+    #[cfg(feature = "auth")]
+    #[test]
+    fn authentication() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        let start = Request::StartBootload(StartBootload {
+            start_addr: 16 * 1024,
+            length: 4 * 1024,
+            crc32: 0,
+            nonce: TEST_NONCE,
+            app_version: 0,
+            allow_rollback: false,
+        });
+        let good = crate::auth::respond(&TEST_AUTH_KEY, &[1; 16]);
+
+        let seq: &[(Request<'_>, Result<Response<'_>, ResponseError>)] = &[
+            // Harmless requests are always allowed
+            (Request::Ping(1), Ok(Response::Pong(1))),
+            (start.clone(), Err(ResponseError::Unauthorized)),
+            (
+                Request::ReadRange {
+                    start_addr: 0,
+                    len: 16,
+                },
+                Err(ResponseError::Unauthorized),
+            ),
+            // No challenge has been issued yet
+            (
+                Request::Authenticate { response: good },
+                Err(ResponseError::Unauthorized),
+            ),
+            (
+                Request::GetChallenge,
+                Ok(Response::Challenge { nonce: [1; 16] }),
+            ),
+            (
+                Request::Authenticate { response: [0; 32] },
+                Err(ResponseError::Unauthorized),
+            ),
+            // That challenge has been used up
+            (
+                Request::Authenticate { response: good },
+                Err(ResponseError::Unauthorized),
+            ),
+            (start.clone(), Err(ResponseError::Unauthorized)),
+            (
+                Request::GetChallenge,
+                Ok(Response::Challenge { nonce: [2; 16] }),
+            ),
+            (
+                Request::Authenticate {
+                    response: crate::auth::respond(&TEST_AUTH_KEY, &[2; 16]),
+                },
+                Ok(Response::Authenticated),
+            ),
+            (start, Ok(Response::BootloadStarted)),
+        ];
+        run_sequence(&mut machine, seq);
+    }
+
+    /// Flash is only ever erased or programmed inside an application slot
+    fn check_log<L: Layout>(hw: &mut RamFlash<L>) {
+        let params = L::PARAMETERS;
+        let in_app = |start: u32, len: u32| {
+            let within = |(rstart, rend): (u32, u32)| start >= rstart && start + len <= rend;
+            within(params.valid_app_range) || params.alt_app_range.is_some_and(within)
+        };
+        for op in hw.log() {
+            match *op {
+                Op::Erase { start, len } | Op::Program { start, len } => {
+                    assert!(in_app(start, len), "{:?}", op)
+                }
+                Op::Read { .. } | Op::WriteSettings { .. } => {}
+            }
+        }
+        hw.clear_log();
+    }
+
+    /// Try every sequence of a few requests, checking each step against the
+    /// transition table
+    #[test]
+    fn explore_modes() {
+        use super::{transitions, ModeKind};
+
+        let image = [16u8; 2048];
+        let settings = app_settings(&image);
+        let start = |start_addr| {
+            Request::StartBootload(StartBootload {
+                start_addr,
+                length: 2048,
+                crc32: CRC.checksum(&image),
+                nonce: TEST_NONCE,
+                app_version: 0,
+                allow_rollback: false,
+            })
+        };
+        let first = chunk(16 * 1024, 16);
+        let requests = [
+            start(16 * 1024),
+            start(18 * 1024),
+            first.req(),
+            Request::WriteSettings { data: &settings },
+            Request::CompleteBootload { boot: None },
+            Request::CompleteBootload {
+                boot: Some(BootCommand::BootIfBootable),
+            },
+            Request::AbortBootload,
+            Request::Boot(BootCommand::BootIfBootable),
+            Request::Boot(BootCommand::ForceBoot),
+            Request::GetStatus,
+        ];
+        const DEPTH: u32 = 4;
+
+        for n in 0..requests.len().pow(DEPTH) {
+            let mut machine = Machine::new(test_flash::<G031>());
+            authenticate(&mut machine);
+            let mut n = n;
+            for _ in 0..DEPTH {
+                let req = &requests[n % requests.len()];
+                n /= requests.len();
+
+                let from = machine.mode.kind();
+                let resp = machine.dispatch(req.clone());
+                let to = machine.mode.kind();
+                assert!(
+                    transitions(from, req).contains(&to),
+                    "{:?} -> {:?} on {:?}",
+                    from,
+                    to,
+                    req
+                );
+                check_log(machine.hardware_mut());
+
+                // Booting is what was promised, no more and no less
+                let will_boot = matches!(
+                    resp,
+                    Ok(Response::ConfirmBootCmd {
+                        will_boot: true,
+                        ..
+                    }) | Ok(Response::ConfirmComplete {
+                        will_boot: true,
+                        ..
+                    })
+                );
+                let pending = to == ModeKind::BootPending;
+                let newly_pending = pending && from != ModeKind::BootPending;
+                assert!(!will_boot || pending, "{:?} on {:?}", resp, req);
+                assert!(!newly_pending || will_boot, "{:?} on {:?}", resp, req);
+            }
+        }
+    }
+
+    /// Random frames and requests, straight off the wire
+    mod props {
+        use super::{authenticate, check_log, test_flash, G031Ab};
+        use crate::{
+            icd::{
+                settings_to_vec, BootCommand, DataChunk, Request, RequestEnvelope, Setting,
+                SettingVal, Slot, StartBootload,
+            },
+            machine::Machine,
+            mock::RamFlash,
+            CRC,
+        };
+        use proptest::{collection::vec, prelude::*};
+
+        /// The size of the device's frame buffer
+        const BUF_LEN: usize = 3072;
+
+        /// Send one frame, which must always get a response that fits
+        fn send(machine: &mut Machine<RamFlash<G031Ab>>, frame: &[u8]) {
+            let mut buf = [0u8; BUF_LEN];
+            let used = frame.len().min(BUF_LEN);
+            buf[..used].copy_from_slice(&frame[..used]);
+            let resp = if frame.len() > BUF_LEN {
+                machine.nak_overfill(&mut buf)
+            } else {
+                machine.process(&mut buf)
+            };
+            assert!(resp.is_some(), "no response to {:02x?}", frame);
+        }
+
+        /// Addresses near the interesting boundaries, and anywhere at all
+        fn addr() -> impl Strategy<Value = u32> {
+            prop_oneof![
+                Just(16 * 1024),
+                Just(40 * 1024),
+                (0u32..36).prop_map(|n| n * 2048),
+                0u32..(72 * 1024),
+                any::<u32>(),
+            ]
+        }
+
+        fn length() -> impl Strategy<Value = u32> {
+            prop_oneof![(0u32..16).prop_map(|n| n * 2048), any::<u32>()]
+        }
+
+        /// The raw parts of a request, which borrows its data
+        #[derive(Debug, Clone)]
+        struct Raw {
+            kind: u8,
+            a: u32,
+            b: u32,
+            flag: bool,
+            data: Vec<u8>,
+        }
+
+        fn raw() -> impl Strategy<Value = Raw> {
+            let data = prop_oneof![
+                vec(any::<u8>(), 0..64),
+                any::<u8>().prop_map(|fill| vec![fill; 2048]),
+                // Settings describing an image, with any length and CRC
+                (any::<u32>(), any::<u32>(), any::<bool>()).prop_map(|(len, crc, slot_b)| {
+                    let slot = if slot_b { Slot::B } else { Slot::A };
+                    settings_to_vec(&[
+                        Setting {
+                            name_ascii: slot.len_setting(),
+                            val: SettingVal::U32(len),
+                        },
+                        Setting {
+                            name_ascii: slot.crc_setting(),
+                            val: SettingVal::U32(crc),
+                        },
+                    ])
+                }),
+            ];
+            (0u8..12, addr(), length(), any::<bool>(), data).prop_map(|(kind, a, b, flag, data)| {
+                Raw {
+                    kind,
+                    a,
+                    b,
+                    flag,
+                    data,
+                }
+            })
+        }
+
+        fn request(raw: &Raw) -> Request<'_> {
+            let boot = if raw.flag {
+                BootCommand::ForceBoot
+            } else {
+                BootCommand::BootIfBootable
+            };
+            match raw.kind {
+                0 => Request::GetParameters,
+                1 => Request::StartBootload(StartBootload {
+                    start_addr: raw.a,
+                    length: raw.b,
+                    crc32: CRC.checksum(&raw.data),
+                    nonce: super::TEST_NONCE,
+                    app_version: raw.b,
+                    allow_rollback: raw.flag,
+                }),
+                2 | 3 => Request::DataChunk(DataChunk {
+                    data_addr: raw.a,
+                    sub_crc32: CRC.checksum(&raw.data),
+                    data: &raw.data,
+                }),
+                4 => Request::CompleteBootload { boot: None },
+                5 => Request::GetSettings,
+                6 => Request::WriteSettings { data: &raw.data },
+                7 => Request::GetStatus,
+                8 => Request::ReadRange {
+                    start_addr: raw.a,
+                    len: raw.b,
+                },
+                9 => Request::AbortBootload,
+                10 => Request::IsBootable,
+                // `Boot` would leave the bootloader for good
+                _ if raw.flag => Request::GetSlots,
+                _ => Request::CompleteBootload { boot: Some(boot) },
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn random_frames(frames in vec(vec(any::<u8>(), 0..4096), 1..8)) {
+                let mut machine = Machine::new(test_flash::<G031Ab>());
+                authenticate(&mut machine);
+                for mut frame in frames {
+                    frame.push(0);
+                    send(&mut machine, &frame);
+                    check_log(machine.hardware_mut());
+                }
+            }
+
+            #[test]
+            fn random_requests(raws in vec(raw(), 1..24)) {
+                let mut machine = Machine::new(test_flash::<G031Ab>());
+                authenticate(&mut machine);
+                for (seq, raw) in raws.iter().enumerate() {
+                    let frame = RequestEnvelope {
+                        seq: seq as u32,
+                        req: request(raw),
+                    }
+                    .encode_to_vec();
+                    send(&mut machine, &frame);
+                    check_log(machine.hardware_mut());
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,485 @@
+//! In-memory hardware, for tests and simulators
+//!
+//! [`RamFlash`] behaves like NOR flash: erasing sets bytes to `0xFF`, and
+//! programming can only clear bits, so programming over data that wasn't
+//! erased first leaves a mix of the two rather than the new data.
+//!
+//! Faults can be injected with [`RamFlash::inject`], to check that damage
+//! to an image is always caught. Like on real hardware, `Flash` has no way
+//! to report a failed erase or program, so the machine never hears about
+//! them directly.
+//!
+//! For a simulated device that survives restarts, [`RamFlash::open`] keeps
+//! everything in a file.
+
+use std::{fs, marker::PhantomData, path::PathBuf};
+
+use crate::{
+    icd::{Parameters, Slot},
+    machine::Flash,
+    vectors::MemoryMap,
+};
+
+/// The memory layout of a [`RamFlash`]
+pub trait Layout {
+    const PARAMETERS: Parameters;
+
+    /// See [`Flash::SETTINGS_RANGE`]
+    const SETTINGS_RANGE: Option<(u32, u32)> = None;
+
+    /// See [`Flash::MEMORY_MAP`]
+    const MEMORY_MAP: Option<MemoryMap> = None;
+
+    /// See [`Flash::APP_PUBLIC_KEY`]
+    #[cfg(feature = "ed25519")]
+    const APP_PUBLIC_KEY: [u8; 32];
+
+    /// Boot the application with its vector table at `vectors`. There's no
+    /// application to run, so by default this panics.
+    fn boot(vectors: u32) -> ! {
+        panic!("RamFlash can't boot an application at {:#x}", vectors);
+    }
+}
+
+/// An operation performed on a [`RamFlash`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Erase { start: u32, len: u32 },
+    Program { start: u32, len: u32 },
+    Read { start: u32, len: u32 },
+    WriteSettings { len: u32 },
+}
+
+/// Something going wrong with a [`RamFlash`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// The next erase covering `addr` does nothing
+    Erase { addr: u32 },
+    /// The next program covering `addr` does nothing
+    Program { addr: u32 },
+    /// Reads of `addr` return it with `bit` flipped, until faults are cleared
+    BitFlip { addr: u32, bit: u8 },
+    /// Power is lost when the next erase or program reaches `addr`. Only the
+    /// part before `addr` is done, and nothing else is erased or programmed
+    /// until [`RamFlash::power_on`].
+    PowerLoss { addr: u32 },
+}
+
+/// Flash, and the settings page, held in memory
+pub struct RamFlash<L: Layout> {
+    flash: Vec<u8>,
+    settings: Vec<u8>,
+    log: Vec<Op>,
+    faults: Vec<Fault>,
+    powered: bool,
+    read_buf: Vec<u8>,
+    min_version: u32,
+    active: Slot,
+    attempts: u32,
+    #[cfg(feature = "auth")]
+    auth_key: [u8; 32],
+    #[cfg(feature = "auth")]
+    rng: u8,
+    #[cfg(feature = "encrypt")]
+    encryption_key: [u8; 32],
+    file: Option<PathBuf>,
+    _layout: PhantomData<L>,
+}
+
+impl<L: Layout> Default for RamFlash<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L: Layout> RamFlash<L> {
+    /// Fully erased flash
+    pub fn new() -> Self {
+        let (start, end) = L::PARAMETERS.valid_flash_range;
+        RamFlash {
+            flash: vec![0xFF; (end - start) as usize],
+            // The settings page starts with its length
+            settings: vec![0xFF; 4 + L::PARAMETERS.settings_max as usize],
+            log: Vec::new(),
+            faults: Vec::new(),
+            powered: true,
+            read_buf: Vec::new(),
+            min_version: 0,
+            active: Slot::A,
+            attempts: 0,
+            #[cfg(feature = "auth")]
+            auth_key: [0; 32],
+            #[cfg(feature = "auth")]
+            rng: 0,
+            #[cfg(feature = "encrypt")]
+            encryption_key: [0; 32],
+            file: None,
+            _layout: PhantomData,
+        }
+    }
+
+    /// Flash kept in a file: loaded from `path` if it exists, otherwise
+    /// fully erased, and saved back after every change.
+    ///
+    /// The file holds flash, the settings page, and the state kept outside
+    /// of them (the minimum version, active slot and boot attempts), but
+    /// not keys.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let mut hw = Self::new();
+        if path.exists() {
+            let saved = fs::read(&path)?;
+            if !hw.restore(&saved) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "not a flash image for this layout",
+                ));
+            }
+        }
+        hw.file = Some(path);
+        Ok(hw)
+    }
+
+    /// Everything [`RamFlash::open`] keeps
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.flash);
+        data.extend_from_slice(&self.settings);
+        data.extend_from_slice(&self.min_version.to_le_bytes());
+        data.push(match self.active {
+            Slot::A => 0,
+            Slot::B => 1,
+        });
+        data.extend_from_slice(&self.attempts.to_le_bytes());
+        data
+    }
+
+    /// Load what [`RamFlash::to_bytes`] saved. Returns whether it fit this
+    /// layout.
+    fn restore(&mut self, data: &[u8]) -> bool {
+        if data.len() != self.to_bytes().len() {
+            return false;
+        }
+        let (flash, rest) = data.split_at(self.flash.len());
+        let (settings, rest) = rest.split_at(self.settings.len());
+        let (min_version, rest) = rest.split_at(4);
+        let (active, attempts) = rest.split_at(1);
+        self.active = match active[0] {
+            0 => Slot::A,
+            1 => Slot::B,
+            _ => return false,
+        };
+        self.flash.copy_from_slice(flash);
+        self.settings.copy_from_slice(settings);
+        self.min_version = u32::from_le_bytes(min_version.try_into().unwrap());
+        self.attempts = u32::from_le_bytes(attempts.try_into().unwrap());
+        true
+    }
+
+    /// Save to the file given to [`RamFlash::open`], if there is one
+    fn save(&self) {
+        if let Some(path) = self.file.as_ref() {
+            if let Err(e) = fs::write(path, self.to_bytes()) {
+                panic!("saving flash to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    #[cfg(feature = "auth")]
+    pub fn set_auth_key(&mut self, key: [u8; 32]) {
+        self.auth_key = key;
+    }
+
+    #[cfg(feature = "encrypt")]
+    pub fn set_encryption_key(&mut self, key: [u8; 32]) {
+        self.encryption_key = key;
+    }
+
+    /// The contents of flash, starting at the beginning of the valid range
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// The contents of flash, e.g. to corrupt an image behind the
+    /// machine's back. Changes made here aren't logged.
+    pub fn flash_mut(&mut self) -> &mut [u8] {
+        &mut self.flash
+    }
+
+    /// The raw settings page
+    pub fn settings(&self) -> &[u8] {
+        &self.settings
+    }
+
+    /// Every erase, program and read so far, oldest first
+    pub fn log(&self) -> &[Op] {
+        &self.log
+    }
+
+    pub fn clear_log(&mut self) {
+        self.log.clear();
+    }
+
+    pub fn inject(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+    }
+
+    /// Has power been lost? See [`Fault::PowerLoss`].
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Restore power after a [`Fault::PowerLoss`]. Flash keeps whatever was
+    /// written before power was lost, so a new `Machine` can be started
+    /// over it to see what the device does after a reset.
+    pub fn power_on(&mut self) {
+        self.powered = true;
+    }
+
+    /// How much of an erase or program of `start..start + len` actually
+    /// happens, taking any one-shot fault matching `is_fault` or power loss
+    /// into account
+    fn faulted_len(&mut self, start: u32, len: u32, is_fault: impl Fn(&Fault) -> bool) -> u32 {
+        if !self.powered {
+            return 0;
+        }
+        let covers = |addr: u32| addr >= start && addr - start < len;
+        let hit = self.faults.iter().position(|f| match f {
+            Fault::PowerLoss { addr } => covers(*addr),
+            f => is_fault(f),
+        });
+        match hit.map(|i| self.faults.remove(i)) {
+            Some(Fault::PowerLoss { addr }) => {
+                self.powered = false;
+                addr - start
+            }
+            Some(_) => 0,
+            None => len,
+        }
+    }
+
+    /// The part of `flash` from `start` to `start + len`. Panics if that's
+    /// outside the valid flash range.
+    fn region(&mut self, start: u32, len: u32) -> &mut [u8] {
+        let (valid_start, valid_end) = L::PARAMETERS.valid_flash_range;
+        let end = start.checked_add(len);
+        match end {
+            Some(end) if start >= valid_start && end <= valid_end => {
+                let offset = (start - valid_start) as usize;
+                &mut self.flash[offset..][..len as usize]
+            }
+            _ => panic!("access outside of flash: {:#x} + {:#x}", start, len),
+        }
+    }
+}
+
+impl<L: Layout> Flash for RamFlash<L> {
+    const PARAMETERS: Parameters = L::PARAMETERS;
+    const SETTINGS_RANGE: Option<(u32, u32)> = L::SETTINGS_RANGE;
+    const MEMORY_MAP: Option<MemoryMap> = L::MEMORY_MAP;
+
+    #[cfg(feature = "ed25519")]
+    const APP_PUBLIC_KEY: [u8; 32] = L::APP_PUBLIC_KEY;
+
+    fn flash_range(&mut self, start: u32, data: &[u8]) {
+        let len = data.len() as u32;
+        self.log.push(Op::Program { start, len });
+        // Out of bounds is a bug in the machine, fault or not
+        self.region(start, len);
+        let done = self.faulted_len(
+            start,
+            len,
+            |f| matches!(f, Fault::Program { addr } if *addr >= start && *addr - start < len),
+        );
+        self.region(start, done)
+            .iter_mut()
+            .zip(data)
+            .for_each(|(b, d)| *b &= d);
+        self.save();
+    }
+
+    fn erase_range(&mut self, start: u32, len: u32) {
+        self.log.push(Op::Erase { start, len });
+        self.region(start, len);
+        let done = self.faulted_len(
+            start,
+            len,
+            |f| matches!(f, Fault::Erase { addr } if *addr >= start && *addr - start < len),
+        );
+        self.region(start, done).fill(0xFF);
+        self.save();
+    }
+
+    fn read_settings_raw(&mut self) -> &[u8] {
+        &self.settings
+    }
+
+    fn write_settings(&mut self, data: &[u8]) {
+        self.log.push(Op::WriteSettings {
+            len: data.len() as u32,
+        });
+        if !self.powered {
+            return;
+        }
+        assert!(data.len() <= self.settings.len(), "settings too long");
+        self.settings.fill(0xFF);
+        self.settings[..data.len()].copy_from_slice(data);
+        self.save();
+    }
+
+    fn read_range(&mut self, start_addr: u32, len: u32) -> &[u8] {
+        self.log.push(Op::Read {
+            start: start_addr,
+            len,
+        });
+        let mut data = self.region(start_addr, len).to_vec();
+        for fault in self.faults.iter() {
+            if let Fault::BitFlip { addr, bit } = fault {
+                if let Some(b) = addr
+                    .checked_sub(start_addr)
+                    .and_then(|i| data.get_mut(i as usize))
+                {
+                    *b ^= 1 << bit;
+                }
+            }
+        }
+        self.read_buf = data;
+        &self.read_buf
+    }
+
+    fn boot(&mut self) -> ! {
+        let vectors = self.app_vectors();
+        L::boot(vectors)
+    }
+
+    #[cfg(feature = "auth")]
+    fn auth_key(&mut self) -> [u8; 32] {
+        self.auth_key
+    }
+
+    #[cfg(feature = "auth")]
+    fn fill_random(&mut self, buf: &mut [u8]) {
+        // Not random, but at least never the same twice
+        self.rng = self.rng.wrapping_add(1);
+        buf.fill(self.rng);
+    }
+
+    #[cfg(feature = "encrypt")]
+    fn encryption_key(&mut self) -> [u8; 32] {
+        self.encryption_key
+    }
+
+    fn min_app_version(&mut self) -> u32 {
+        self.min_version
+    }
+
+    fn set_min_app_version(&mut self, version: u32) {
+        self.min_version = version;
+        self.save();
+    }
+
+    fn active_slot(&mut self) -> Slot {
+        self.active
+    }
+
+    fn set_active_slot(&mut self, slot: Slot) {
+        self.active = slot;
+        self.save();
+    }
+
+    fn boot_attempts(&mut self) -> u32 {
+        self.attempts
+    }
+
+    fn set_boot_attempts(&mut self, attempts: u32) {
+        self.attempts = attempts;
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Op, RamFlash};
+    use crate::{
+        icd::Slot,
+        machine::{test::G031, Flash},
+    };
+
+    #[test]
+    fn nor_semantics() {
+        let mut hw = RamFlash::<G031>::new();
+        hw.flash_range(16 * 1024, &[0x0F; 4]);
+        assert_eq!(hw.read_range(16 * 1024, 4), [0x0F; 4].as_slice());
+
+        // Programming only clears bits
+        hw.flash_range(16 * 1024, &[0xF0; 4]);
+        assert_eq!(hw.read_range(16 * 1024, 4), [0x00; 4].as_slice());
+
+        hw.erase_range(16 * 1024, 2048);
+        assert_eq!(hw.read_range(16 * 1024, 4), [0xFF; 4].as_slice());
+
+        assert_eq!(
+            hw.log(),
+            &[
+                Op::Program {
+                    start: 16 * 1024,
+                    len: 4
+                },
+                Op::Read {
+                    start: 16 * 1024,
+                    len: 4
+                },
+                Op::Program {
+                    start: 16 * 1024,
+                    len: 4
+                },
+                Op::Read {
+                    start: 16 * 1024,
+                    len: 4
+                },
+                Op::Erase {
+                    start: 16 * 1024,
+                    len: 2048
+                },
+                Op::Read {
+                    start: 16 * 1024,
+                    len: 4
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn survives_restarts() {
+        let path = std::env::temp_dir().join(format!("dabble-mock-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut hw = RamFlash::<G031>::open(&path).unwrap();
+        assert!(hw.flash().iter().all(|b| *b == 0xFF));
+        hw.erase_range(16 * 1024, 2048);
+        hw.flash_range(16 * 1024, &[0x0F; 4]);
+        hw.write_settings(&[1, 2, 3]);
+        hw.set_min_app_version(3);
+        hw.set_active_slot(Slot::B);
+
+        let mut hw = RamFlash::<G031>::open(&path).unwrap();
+        assert_eq!(hw.read_range(16 * 1024, 4), [0x0F; 4].as_slice());
+        assert_eq!(&hw.read_settings_raw()[..3], &[1, 2, 3]);
+        assert_eq!(hw.min_app_version(), 3);
+        assert_eq!(hw.active_slot(), Slot::B);
+
+        std::fs::write(&path, [0u8; 16]).unwrap();
+        assert!(RamFlash::<G031>::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_bounds() {
+        RamFlash::<G031>::new().read_range(63 * 1024, 2048);
+    }
+}
@@ -0,0 +1,98 @@
+//! An async device loop, for `embedded-io-async` serial ports
+//!
+//! [`run`] only waits on `rx` and `tx`, so the rest of the firmware (status
+//! LEDs, watchdogs, timeouts) can run alongside it on the same executor,
+//! e.g. by `select`ing it against a timer to give up waiting for a host.
+
+use core::convert::Infallible;
+
+use embedded_io_async::{Read, Write};
+
+use crate::{
+    machine::{Flash, Machine},
+    transport::Framer,
+};
+
+/// Why [`run`] stopped
+#[derive(Debug, PartialEq)]
+pub enum Error<R, W> {
+    Read(R),
+    Write(W),
+    /// `rx` has no more bytes to give, and never will
+    Eof,
+}
+
+/// Talk to a host over `rx` and `tx`, forever.
+///
+/// Frames are assembled in `buf`, which must be large enough for the
+/// largest request, and the largest response. Each response is sent, and
+/// flushed, before [`Machine::check_after_send`] is called, so a request to
+/// boot is only acted on once the host has been told.
+///
+/// Cancelling this drops any frame that was partly received, but leaves
+/// `machine` in a consistent state.
+pub async fn run<HW: Flash, R: Read, W: Write>(
+    machine: &mut Machine<HW>,
+    rx: &mut R,
+    tx: &mut W,
+    buf: &mut [u8],
+) -> Result<Infallible, Error<R::Error, W::Error>> {
+    let mut framer = Framer::new(buf);
+    let mut chunk = [0u8; 32];
+    loop {
+        let used = rx.read(&mut chunk).await.map_err(Error::Read)?;
+        if used == 0 {
+            return Err(Error::Eof);
+        }
+
+        for byte in &chunk[..used] {
+            if !framer.feed(*byte) {
+                continue;
+            }
+            if let Some(msg) = framer.respond(machine) {
+                tx.write_all(msg).await.map_err(Error::Write)?;
+                tx.flush().await.map_err(Error::Write)?;
+            }
+            machine.check_after_send();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "use-std"))]
+mod test {
+    use super::{run, Error};
+    use crate::{
+        icd::{decode_in_place, Request, RequestEnvelope, Response, ResponseEnvelope},
+        machine::{
+            test::{test_flash, G031},
+            Machine,
+        },
+    };
+    use embassy_futures::block_on;
+
+    #[test]
+    fn ping() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        let mut rx = Vec::new();
+        for seq in 0..3 {
+            let env = RequestEnvelope {
+                seq,
+                req: Request::Ping(seq),
+            };
+            rx.extend(env.encode_to_vec());
+        }
+        let mut tx = Vec::new();
+        let mut buf = [0u8; 3072];
+
+        let res = block_on(run(&mut machine, &mut rx.as_slice(), &mut tx, &mut buf));
+        assert_eq!(res, Err(Error::Eof));
+
+        let mut frames = tx.split_mut(|b| *b == 0);
+        for seq in 0..3 {
+            let frame = frames.next().unwrap();
+            let env: ResponseEnvelope<'_> = decode_in_place(frame).unwrap();
+            assert_eq!(env.seq, Some(seq));
+            assert_eq!(env.resp, Ok(Response::Pong(seq)));
+        }
+    }
+}
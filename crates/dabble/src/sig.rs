@@ -0,0 +1,52 @@
+//! Application image signatures
+//!
+//! Images are signed with Ed25519ph: the signature is made over the SHA-512
+//! hash of the application region, so the device can hash the image one
+//! chunk at a time instead of needing it in one contiguous slice.
+//!
+//! The hashed region is the full `app_len` bytes starting at the beginning
+//! of the application range, exactly as the device reads it back from flash,
+//! followed by the application's version (see
+//! [`Slot::version_setting`](crate::icd::Slot::version_setting)) as a
+//! little-endian `u32`. Signing the version stops a host from passing off
+//! an old image as a new one.
+
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+/// The name of the setting holding the 64-byte image signature
+pub const SIG_SETTING: &[u8] = b"app_sig";
+
+/// Domain separation for image signatures
+pub const SIG_CONTEXT: &[u8] = b"dabble-app";
+
+/// Check a signature over an image and its version, which have been fed to
+/// `hasher`
+pub fn verify(public_key: &[u8; 32], signature: &[u8; 64], hasher: Sha512) -> bool {
+    let key = match VerifyingKey::from_bytes(public_key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    let sig = Signature::from_bytes(signature);
+    key.verify_prehashed(hasher, Some(SIG_CONTEXT), &sig)
+        .is_ok()
+}
+
+/// Sign a complete (already padded) application image, and its version
+pub fn sign(secret_key: &[u8; 32], image: &[u8], version: u32) -> [u8; 64] {
+    let key = SigningKey::from_bytes(secret_key);
+    let mut hasher = Sha512::new();
+    hasher.update(image);
+    hasher.update(version.to_le_bytes());
+    // This can only fail if the context is too long, and ours is not.
+    key.sign_prehashed(hasher, Some(SIG_CONTEXT))
+        .unwrap()
+        .to_bytes()
+}
+
+/// Get the public key matching a secret key
+pub fn public_key(secret_key: &[u8; 32]) -> [u8; 32] {
+    SigningKey::from_bytes(secret_key)
+        .verifying_key()
+        .to_bytes()
+}
@@ -0,0 +1,363 @@
+//! Links between a host and a device
+//!
+//! On the device, a [`Transport`] moves bytes to and from the host without
+//! blocking, and a [`Framer`] splits them into frames for a
+//! [`Machine`](crate::machine::Machine) and sends back its responses.
+//!
+//! On the host, the [`Client`](crate::client::Client) works over anything
+//! that is `Read + Write`: serial ports and `TcpStream`s already are, and
+//! [`Stdio`] and [`Loopback`] cover stdin/stdout and a device in the same
+//! process. [`Stream`] turns any of these into a device-side [`Transport`].
+
+use crate::machine::{Flash, Machine};
+
+/// A byte link, as seen from the device
+pub trait Transport {
+    type Error;
+
+    /// Read whatever bytes are available, without waiting for more.
+    /// Returns how many were read, which may be none.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    /// Write as many bytes as can be accepted without waiting. Returns how
+    /// many were written, which may be none.
+    fn write(&mut self, data: &[u8]) -> Result<usize, Self::Error>;
+
+    /// Finish sending everything written so far
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Splits the bytes from a [`Transport`] into frames for a [`Machine`]
+pub struct Framer<B: AsMut<[u8]>> {
+    buf: B,
+    idx: usize,
+    overfill: bool,
+}
+
+impl<B: AsMut<[u8]>> Framer<B> {
+    /// Frames are assembled in `buf`, which must be large enough for the
+    /// largest request, and the largest response
+    pub fn new(buf: B) -> Self {
+        Framer {
+            buf,
+            idx: 0,
+            overfill: false,
+        }
+    }
+
+    /// Read what is available from `link`. If that completes a frame, hand
+    /// it to `machine`, send the response, and call
+    /// [`Machine::check_after_send`].
+    ///
+    /// Returns whether a frame was handled.
+    pub fn poll<HW: Flash, T: Transport>(
+        &mut self,
+        machine: &mut Machine<HW>,
+        link: &mut T,
+    ) -> Result<bool, T::Error> {
+        loop {
+            let mut byte = [0u8];
+            if link.read(&mut byte)? == 0 {
+                return Ok(false);
+            }
+            if self.feed(byte[0]) {
+                break;
+            }
+        }
+
+        if let Some(mut msg) = self.respond(machine) {
+            while !msg.is_empty() {
+                let used = link.write(msg)?;
+                msg = &msg[used..];
+            }
+            link.flush()?;
+        }
+        machine.check_after_send();
+        Ok(true)
+    }
+
+    /// Add a byte to the frame. Returns whether that completed it.
+    pub(crate) fn feed(&mut self, byte: u8) -> bool {
+        match self.buf.as_mut().get_mut(self.idx) {
+            Some(cur) => {
+                *cur = byte;
+                self.idx += 1;
+            }
+            // Too long for our buffer, discard the rest of the frame
+            None => self.overfill = true,
+        }
+        byte == 0
+    }
+
+    /// Hand a completed frame to `machine`, and start on the next one
+    pub(crate) fn respond<HW: Flash>(&mut self, machine: &mut Machine<HW>) -> Option<&[u8]> {
+        let overfill = self.overfill;
+        self.idx = 0;
+        self.overfill = false;
+        if overfill {
+            machine.nak_overfill(self.buf.as_mut())
+        } else {
+            machine.process(self.buf.as_mut())
+        }
+    }
+}
+
+#[cfg(feature = "use-std")]
+pub use self::host::{Link, Loopback, Stdio, Stream};
+
+#[cfg(feature = "use-std")]
+mod host {
+    use super::{Framer, Transport};
+    use crate::machine::{Flash, Machine};
+    use std::{
+        collections::VecDeque,
+        io::{ErrorKind, Read, Write},
+    };
+
+    /// Anything a [`Client`](crate::client::Client) can talk over, for
+    /// choosing a link at runtime with `Box<dyn Link>`
+    pub trait Link: Read + Write {}
+    impl<T: Read + Write> Link for T {}
+
+    /// A device-side [`Transport`] over a `Read + Write` stream, such as a
+    /// serial port or a `TcpStream`.
+    ///
+    /// Timeouts and `WouldBlock` mean no bytes are available. The stream
+    /// ending is an `UnexpectedEof` error.
+    pub struct Stream<T: Read + Write>(pub T);
+
+    impl<T: Read + Write> Transport for Stream<T> {
+        type Error = std::io::Error;
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            match self.0.read(buf) {
+                Ok(0) if !buf.is_empty() => Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => Ok(n),
+                Err(e) if is_no_data(&e) => Ok(0),
+                Err(e) => Err(e),
+            }
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<usize, Self::Error> {
+            match self.0.write(data) {
+                Ok(n) => Ok(n),
+                Err(e) if is_no_data(&e) => Ok(0),
+                Err(e) => Err(e),
+            }
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            self.0.flush()
+        }
+    }
+
+    fn is_no_data(e: &std::io::Error) -> bool {
+        matches!(
+            e.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+        )
+    }
+
+    /// Stdin and stdout, e.g. to talk through a pipe to another process.
+    ///
+    /// Reads block until input arrives, so a `Client` can't time out.
+    pub struct Stdio {
+        stdin: std::io::Stdin,
+        stdout: std::io::Stdout,
+    }
+
+    impl Stdio {
+        pub fn new() -> Self {
+            Stdio {
+                stdin: std::io::stdin(),
+                stdout: std::io::stdout(),
+            }
+        }
+    }
+
+    impl Default for Stdio {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Read for Stdio {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.stdin.read(buf)
+        }
+    }
+
+    impl Write for Stdio {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.stdout.write(data)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.stdout.flush()
+        }
+    }
+
+    /// The two directions of a [`Loopback`], as the device sees them
+    #[derive(Default)]
+    struct Pipe {
+        to_device: VecDeque<u8>,
+        to_host: Vec<u8>,
+    }
+
+    impl Transport for Pipe {
+        type Error = core::convert::Infallible;
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let n = buf.len().min(self.to_device.len());
+            buf.iter_mut()
+                .zip(self.to_device.drain(..n))
+                .for_each(|(b, d)| *b = d);
+            Ok(n)
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<usize, Self::Error> {
+            self.to_host.extend_from_slice(data);
+            Ok(data.len())
+        }
+    }
+
+    /// A device running in the same process. Everything written is handled
+    /// by the machine straight away, and its responses are read back.
+    ///
+    /// Reads with nothing to return fail with `TimedOut`, like a serial
+    /// port with a timeout.
+    ///
+    /// Faults can be injected to check how a host copes with a noisy line.
+    pub struct Loopback<HW: Flash> {
+        machine: Machine<HW>,
+        framer: Framer<Vec<u8>>,
+        pipe: Pipe,
+        /// Where the next byte written falls in its frame
+        frame_pos: usize,
+        corrupt: usize,
+        corrupt_resp: usize,
+        drop: usize,
+    }
+
+    impl<HW: Flash> Loopback<HW> {
+        /// `buf_len` is the size of the device's frame buffer
+        pub fn new(machine: Machine<HW>, buf_len: usize) -> Self {
+            Loopback {
+                machine,
+                framer: Framer::new(vec![0; buf_len]),
+                pipe: Pipe::default(),
+                frame_pos: 0,
+                corrupt: 0,
+                corrupt_resp: 0,
+                drop: 0,
+            }
+        }
+
+        pub fn machine(&mut self) -> &mut Machine<HW> {
+            &mut self.machine
+        }
+
+        /// Flip a bit in each of the next `count` frames sent to the device
+        pub fn corrupt_requests(&mut self, count: usize) {
+            self.corrupt = count;
+        }
+
+        /// Flip a bit in each of the device's next `count` responses
+        pub fn corrupt_responses(&mut self, count: usize) {
+            self.corrupt_resp = count;
+        }
+
+        /// Lose the device's responses to the next `count` frames
+        pub fn drop_responses(&mut self, count: usize) {
+            self.drop = count;
+        }
+
+        /// Queue `data` for the host to read, as if the device had sent it
+        pub fn inject(&mut self, data: &[u8]) {
+            self.pipe.to_host.extend_from_slice(data);
+        }
+    }
+
+    impl<HW: Flash> Write for Loopback<HW> {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            for &byte in data {
+                let mut byte = byte;
+                if self.frame_pos == 1 && self.corrupt > 0 {
+                    self.corrupt -= 1;
+                    byte ^= 0x01;
+                }
+                self.frame_pos = if byte == 0 { 0 } else { self.frame_pos + 1 };
+                self.pipe.to_device.push_back(byte);
+            }
+
+            loop {
+                let sent = self.pipe.to_host.len();
+                match self.framer.poll(&mut self.machine, &mut self.pipe) {
+                    Ok(true) => {}
+                    _ => break,
+                }
+                if self.drop > 0 && self.pipe.to_host.len() > sent {
+                    self.drop -= 1;
+                    self.pipe.to_host.truncate(sent);
+                }
+                if self.corrupt_resp > 0 && self.pipe.to_host.len() > sent + 1 {
+                    self.corrupt_resp -= 1;
+                    self.pipe.to_host[sent + 1] ^= 0x01;
+                }
+            }
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<HW: Flash> Read for Loopback<HW> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pipe.to_host.is_empty() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let n = buf.len().min(self.pipe.to_host.len());
+            buf[..n].copy_from_slice(&self.pipe.to_host[..n]);
+            self.pipe.to_host.drain(..n);
+            Ok(n)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "use-std"))]
+mod test {
+    use super::Loopback;
+    use crate::{
+        client::Client,
+        icd::{Request, Response},
+        machine::{
+            test::{authenticate, test_flash, G031},
+            Machine,
+        },
+    };
+    use std::io::{Read, Write};
+
+    #[test]
+    fn loopback() {
+        let mut machine = Machine::new(test_flash::<G031>());
+        authenticate(&mut machine);
+        let mut link = Loopback::new(machine, 64);
+
+        // Too long for the device's buffer, which gets a NAK and then
+        // recovers for the next frame
+        link.write_all(&[0x55; 100]).unwrap();
+        link.write_all(&[0]).unwrap();
+        let mut resp = [0u8; 64];
+        assert!(link.read(&mut resp).unwrap() > 0);
+
+        let mut client = Client::new(link);
+        assert_eq!(
+            client.request(&Request::Ping(42)).unwrap(),
+            Response::Pong(42)
+        );
+    }
+}
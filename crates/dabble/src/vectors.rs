@@ -0,0 +1,98 @@
+//! Cortex-M vector tables
+//!
+//! An application starts with its vector table: the initial stack pointer,
+//! followed by the reset vector. Jumping to an erased or half-written
+//! region would load garbage into both, so they are checked first.
+
+/// Where flash and RAM are in the device's memory map
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryMap {
+    /// The address of offset 0 of the flash, e.g. `0x0800_0000`
+    pub flash_base: u32,
+    /// The start and end address of RAM
+    pub ram: (u32, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VectorError {
+    TooShort,
+    BadStackPointer(u32),
+    BadResetVector(u32),
+}
+
+/// Check the start of a vector table.
+///
+/// `ram` and `app` are the start and end addresses of RAM and of the
+/// application region. The initial stack pointer may be the end of RAM,
+/// as the stack grows down.
+pub fn check(table: &[u8], ram: (u32, u32), app: (u32, u32)) -> Result<(), VectorError> {
+    let word = |i: usize| {
+        table
+            .get(i * 4..(i + 1) * 4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+    };
+    let (sp, reset) = match (word(0), word(1)) {
+        (Some(sp), Some(reset)) => (sp, reset),
+        _ => return Err(VectorError::TooShort),
+    };
+
+    if sp <= ram.0 || sp > ram.1 {
+        return Err(VectorError::BadStackPointer(sp));
+    }
+
+    // Cortex-M only runs Thumb code, so the lowest bit must be set
+    let thumb = reset & 1 == 1;
+    let addr = reset & !1;
+    if !thumb || addr < app.0 || addr >= app.1 {
+        return Err(VectorError::BadResetVector(reset));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, VectorError};
+
+    const RAM: (u32, u32) = (0x2000_0000, 0x2000_2000);
+    const APP: (u32, u32) = (0x0800_4000, 0x0800_8000);
+
+    fn table(sp: u32, reset: u32) -> [u8; 8] {
+        let mut out = [0u8; 8];
+        out[..4].copy_from_slice(&sp.to_le_bytes());
+        out[4..].copy_from_slice(&reset.to_le_bytes());
+        out
+    }
+
+    #[test]
+    fn vector_tables() {
+        assert_eq!(check(&table(0x2000_2000, 0x0800_40C1), RAM, APP), Ok(()));
+        assert_eq!(check(&table(0x2000_1000, 0x0800_7FFF), RAM, APP), Ok(()));
+
+        // Erased flash
+        assert_eq!(
+            check(&[0xFF; 8], RAM, APP),
+            Err(VectorError::BadStackPointer(0xFFFF_FFFF))
+        );
+        assert_eq!(check(&[0xFF; 6], RAM, APP), Err(VectorError::TooShort));
+
+        assert_eq!(
+            check(&table(0x2000_0000, 0x0800_40C1), RAM, APP),
+            Err(VectorError::BadStackPointer(0x2000_0000))
+        );
+        // Not Thumb
+        assert_eq!(
+            check(&table(0x2000_2000, 0x0800_40C0), RAM, APP),
+            Err(VectorError::BadResetVector(0x0800_40C0))
+        );
+        // Into the bootloader
+        assert_eq!(
+            check(&table(0x2000_2000, 0x0800_00C1), RAM, APP),
+            Err(VectorError::BadResetVector(0x0800_00C1))
+        );
+        assert_eq!(
+            check(&table(0x2000_2000, 0x0800_8001), RAM, APP),
+            Err(VectorError::BadResetVector(0x0800_8001))
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! A simulated dabble device, for developing and testing host tools
+//! without hardware.
+//!
+//! The simulated device has the same memory layout as `brain-modem`, and
+//! talks the protocol over TCP instead of a serial port.
+
+use std::{
+    io::ErrorKind,
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::exit,
+};
+
+use clap::Parser;
+use squid_boot::{
+    icd::Parameters,
+    machine::Machine,
+    mock::{Layout, RamFlash},
+    transport::{Framer, Stream},
+};
+
+/// The simulated device's memory layout
+struct Sim;
+
+//  0KiB - 12KiB: Bootloader
+// 12KiB - 14KiB: Bootloader state, kept by `RamFlash` outside of flash
+// 14KiB - 16KiB: Settings
+// 16KiB - 32KiB: Application
+impl Layout for Sim {
+    const PARAMETERS: Parameters = Parameters {
+        settings_max: 2 * 1024,
+        data_chunk_size: 2 * 1024,
+        valid_flash_range: (0, 32 * 1024),
+        valid_app_range: (16 * 1024, 32 * 1024),
+        read_max: 2 * 1024,
+        alt_app_range: None,
+    };
+
+    fn boot(vectors: u32) -> ! {
+        // There's no application to run, so leaving the bootloader is
+        // the end of the simulation
+        println!("Booting the application at {:#x}", vectors);
+        exit(0);
+    }
+}
+
+#[derive(Parser)]
+#[command(about = "Simulate a dabble device, listening on a TCP socket")]
+struct Args {
+    /// Where to listen for a host
+    #[arg(long, default_value = "127.0.0.1:4000")]
+    listen: String,
+
+    /// Keep the contents of flash in this file, so they survive restarts
+    #[arg(long)]
+    flash_file: Option<PathBuf>,
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args) {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let flash = match args.flash_file {
+        Some(path) => RamFlash::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?,
+        None => RamFlash::new(),
+    };
+    let mut machine = Machine::new(flash);
+    let listener = TcpListener::bind(&args.listen)
+        .map_err(|e| format!("listening on {}: {}", args.listen, e))?;
+    println!("Listening on {}", args.listen);
+
+    // Like a device that stays powered, the machine outlives each host
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Accepting a host: {}", e);
+                continue;
+            }
+        };
+        println!("Host connected");
+        match serve(&mut machine, stream) {
+            Ok(()) => println!("Host disconnected"),
+            Err(e) => eprintln!("Host error: {}", e),
+        }
+        // The next host has to authenticate for itself
+        machine.end_session();
+        // Nothing reads the log of flash operations, so don't let it grow
+        machine.hardware_mut().clear_log();
+    }
+    Ok(())
+}
+
+/// Talk to one host, until it disconnects
+fn serve(machine: &mut Machine<RamFlash<Sim>>, stream: TcpStream) -> std::io::Result<()> {
+    let mut buf = [0u8; 3072];
+    let mut framer = Framer::new(&mut buf);
+    let mut link = Stream(stream);
+    loop {
+        match framer.poll(machine, &mut link) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
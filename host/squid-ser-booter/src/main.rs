@@ -1,139 +1,353 @@
-use std::{time::Duration, io::ErrorKind, thread::sleep};
-
-use squid_boot::{icd::{Request, Response, Parameters, ResponseError, StartBootload, DataChunk, decode_in_place}, machine::Bootable};
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
+};
 
-const PARAMS: Parameters = Parameters {
-    settings_max: (2 * 1024) - 4,
-    data_chunk_size: 2 * 1024,
-    valid_flash_range: (0x0000_0000, 0x0000_0000 + (64 * 1024)),
-    valid_app_range: (0x0000_0000 + (16 * 1024), 0x0000_0000 + (64 * 1024)),
-    read_max: 2 * 1024,
+use clap::{Parser, Subcommand};
+use squid_boot::{
+    client::{pad_image, Client},
+    icd::{BootCommand, Request, Response, Setting, SettingVal, Slot},
+    machine::Bootable,
+    sig,
+    transport::Link,
 };
 
+#[derive(Parser)]
+#[command(about = "Talk to a dabble bootloader over a serial port")]
+struct Args {
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Load an application image onto the device
+    Flash(FlashArgs),
+    /// Sign an application image
+    Sign {
+        /// The raw application image (not an ELF!)
+        image: PathBuf,
+
+        /// A secret key file produced by `keygen`
+        #[arg(long)]
+        key: PathBuf,
+
+        /// Where to write the signature
+        #[arg(long, short)]
+        output: PathBuf,
+
+        /// The device's data chunk size, which determines the padding
+        #[arg(long, default_value_t = 2048)]
+        chunk_size: u32,
+
+        /// The image's version, which the signature covers. Flash it with
+        /// the same `--app-version`.
+        #[arg(long, default_value_t = 0)]
+        app_version: u32,
+    },
+    /// Generate a new signing key
+    Keygen {
+        /// Where to write the secret key. The public key is written next to
+        /// it, with a `.pub` extension.
+        output: PathBuf,
+
+        /// Replace existing key files
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(clap::Args)]
+struct FlashArgs {
+    /// The raw application image (not an ELF!), linked to run from slot A,
+    /// the only slot on most devices
+    image: PathBuf,
+
+    /// A signature file produced by `sign`, sent along with the image
+    #[arg(long)]
+    signed: Option<PathBuf>,
+
+    /// The same application, linked to run from slot B, for devices with two
+    /// slots. Each load goes into whichever slot the device isn't booting
+    /// from, so without this, loads into slot B are refused.
+    #[arg(long)]
+    image_b: Option<PathBuf>,
+
+    /// A signature file for `--image-b`
+    #[arg(long)]
+    signed_b: Option<PathBuf>,
+
+    /// Boot the application once it has been loaded
+    #[arg(long)]
+    boot: bool,
+
+    /// The device's pre-shared key, for devices that require authentication
+    #[arg(long)]
+    auth_key: Option<PathBuf>,
+
+    /// The device's image encryption key, for devices that require encrypted images
+    #[arg(long)]
+    encrypt_key: Option<PathBuf>,
+
+    /// The image's version. Devices refuse images older than the newest
+    /// they have accepted. For signed images, this must be the version it
+    /// was signed as.
+    #[arg(long, default_value_t = 0)]
+    app_version: u32,
+
+    /// Load the image even if it is older than the device allows. Only
+    /// honored by devices that authenticate hosts.
+    #[arg(long)]
+    allow_rollback: bool,
+
+    /// Put the image on trial: if it hasn't confirmed itself after this
+    /// many boots, the device goes back to the previous image
+    #[arg(long)]
+    trial: Option<u32>,
+
+    #[arg(long, default_value = "/dev/ttyACM0")]
+    port: String,
+
+    #[arg(long, default_value_t = 115_200)]
+    baud: u32,
+
+    /// Talk to a device over TCP instead of the serial port, e.g. a
+    /// `dabble-sim` listening on `127.0.0.1:4000`
+    #[arg(long)]
+    tcp: Option<String>,
+}
+
 fn main() {
-    let mut port = serialport::new("/dev/ttyACM0", 115_200)
-        .timeout(Duration::from_millis(10))
-        .open().expect("Failed to open port");
-
-    let last = {
-        let mut last = vec![22; 2040];
-        let magic = 0x10101010u32.wrapping_mul(0x10101010u32);
-        last.extend_from_slice(&magic.to_le_bytes());
-        last.extend_from_slice(&0x4F54_CCBCu32.to_le_bytes());
-        last.leak()
+    let args = Args::parse();
+    let res = match args.cmd {
+        Cmd::Flash(args) => flash(args),
+        Cmd::Sign {
+            image,
+            key,
+            output,
+            chunk_size,
+            app_version,
+        } => sign(image, key, output, chunk_size, app_version),
+        Cmd::Keygen { output, force } => keygen(output, force),
     };
 
-    // The sequence of commands sent and expected responses
-    let seq: &[(Request<'static>, Result<Response<'static>, ResponseError>)] = &[
-        (
-            Request::GetParameters,
-            Ok(Response::Parameters(PARAMS)),
-        ),
-        (
-            Request::IsBootable,
-            Ok(Response::BootableStatus(Bootable::NoBadCrc))
-        ),
-        (
-            Request::StartBootload(StartBootload {
-                start_addr: 16 * 1024,
-                length: 8 * 1024,
-                crc32: 0x51f3_6231,
-            }),
-            Ok(Response::BootloadStarted),
-        ),
-        (
-            Request::DataChunk(DataChunk {
-                data_addr: 16 * 1024,
-                sub_crc32: 0x5b54_dab5,
-                data: &[16; 2048],
-            }),
-            Ok(Response::ChunkAccepted {
-                data_addr: 16 * 1024,
-                data_len: 2048,
-                crc32: 0x5b54_dab5,
-            }),
-        ),
-        (
-            Request::DataChunk(DataChunk {
-                data_addr: 18 * 1024,
-                sub_crc32: 0x8c91_77aa,
-                data: &[18; 2048],
-            }),
-            Ok(Response::ChunkAccepted {
-                data_addr: 18 * 1024,
-                data_len: 2048,
-                crc32: 0x8c91_77aa,
-            }),
-        ),
-        (
-            Request::DataChunk(DataChunk {
-                data_addr: 20 * 1024,
-                sub_crc32: 0xf01e_9d3c,
-                data: &[20; 2048],
-            }),
-            Ok(Response::ChunkAccepted {
-                data_addr: 20 * 1024,
-                data_len: 2048,
-                crc32: 0xf01e_9d3c,
-            }),
-        ),
-        (
-            Request::DataChunk(DataChunk {
-                data_addr: 22 * 1024,
-                sub_crc32: 0x514d5248,
-                data: last,
-            }),
-            Ok(Response::ChunkAccepted {
-                data_addr: 22 * 1024,
-                data_len: 2048,
-                crc32: 0x514d5248,
-            }),
-        ),
-        (
-            Request::CompleteBootload { boot: None },
-            Ok(Response::ConfirmComplete { will_boot: false, boot_status: Bootable::Yes }),
+    if let Err(e) = res {
+        eprintln!("Error: {}", e);
+        exit(1);
+    }
+}
+
+fn flash(args: FlashArgs) -> Result<(), String> {
+    let FlashArgs {
+        image,
+        signed,
+        image_b,
+        signed_b,
+        boot,
+        auth_key,
+        encrypt_key,
+        app_version,
+        allow_rollback,
+        trial,
+        port,
+        baud,
+        tcp,
+    } = args;
+    let image_a = read_image(&image, signed.as_deref())?;
+    let image_b = match image_b {
+        Some(path) => Some(read_image(&path, signed_b.as_deref())?),
+        None => None,
+    };
+    let auth_key = match auth_key {
+        Some(path) => Some(read_key(&path, "pre-shared key")?),
+        None => None,
+    };
+    let encrypt_key = match encrypt_key {
+        Some(path) => Some(read_key(&path, "encryption key")?),
+        None => None,
+    };
+
+    let link: Box<dyn Link> = match tcp {
+        Some(addr) => {
+            let stream =
+                TcpStream::connect(&addr).map_err(|e| format!("connecting to {}: {}", addr, e))?;
+            stream
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .map_err(|e| format!("connecting to {}: {}", addr, e))?;
+            Box::new(stream)
+        }
+        None => Box::new(
+            serialport::new(&port, baud)
+                .timeout(Duration::from_millis(10))
+                .open()
+                .map_err(|e| format!("opening {}: {}", port, e))?,
         ),
-    ];
-
-    for (req, exp_resp) in seq.iter() {
-        'retry: loop {
-            println!("Sending: {:?}", req);
-            let to_send = req.encode_to_vec();
-            port.write_all(&to_send).unwrap();
-            let mut rx = Vec::new();
-            'recv: loop {
-                let mut buf = [0u8; 128];
-                match port.read(&mut buf) {
-                    Ok(0) => panic!(),
-                    Ok(n) => rx.extend_from_slice(&buf[..n]),
-                    Err(e) if e.kind() == ErrorKind::TimedOut => continue 'recv,
-                    Err(e) => panic!()
-                }
-
-                match rx.iter().position(|b| *b == 0) {
-                    Some(n) => {
-                        rx.shrink_to(n + 1);
-                        break 'recv;
-                    },
-                    None => continue 'recv,
-                }
-            }
+    };
+    let mut client = Client::new(link);
+    client.set_allow_rollback(allow_rollback);
+
+    if let Some(key) = auth_key.as_ref() {
+        client
+            .authenticate(key)
+            .map_err(|e| format!("authenticating: {:?}", e))?;
+    }
+
+    // An image only runs from the slot it was linked for, and settings
+    // describing it have a different name for each slot
+    let slot = client
+        .load_slot()
+        .map_err(|e| format!("getting slots: {:?}", e))?;
+    let (image, signature) = match slot {
+        Slot::A => image_a,
+        Slot::B => image_b
+            .ok_or("the device is loading slot B, give an image linked for it with --image-b")?,
+    };
+    let mut extra = vec![Setting {
+        name_ascii: slot.version_setting(),
+        val: SettingVal::U32(app_version),
+    }];
+    if let Some(sig) = signature.as_ref() {
+        extra.push(Setting {
+            name_ascii: slot.sig_setting(),
+            val: SettingVal::ByteSlice(sig),
+        });
+    }
+    if let Some(boots) = trial {
+        extra.push(Setting {
+            name_ascii: slot.trial_setting(),
+            val: SettingVal::U32(boots),
+        });
+    }
 
-            match decode_in_place::<Result<Response<'_>, ResponseError>>(&mut rx) {
-                Ok(msg) => {
-                    if &msg == exp_resp {
-                        println!("Got expected response: {:?}", msg);
-                        sleep(Duration::from_secs(3));
-                        break 'retry;
-                    } else {
-                        println!("Unexpected response!");
-                        println!("Expected: {:?}", exp_resp);
-                        println!("Actual:   {:?}", msg);
-                        panic!();
-                    }
-                },
-                Err(_) => todo!(),
+    println!("Flashing {} bytes...", image.len());
+    let status = match encrypt_key {
+        Some(key) => {
+            let mut nonce = [0u8; 12];
+            getrandom::getrandom(&mut nonce).map_err(|e| format!("getting randomness: {}", e))?;
+            client.flash_encrypted(&image, &extra, &key, nonce)
+        }
+        None => client.flash(&image, &extra),
+    }
+    .map_err(|e| format!("flashing: {:?}", e))?;
+    println!("Done: {:?}", status);
+
+    if boot {
+        if !matches!(status, Bootable::Yes { .. }) {
+            return Err("not booting, the application is not bootable".into());
+        }
+        // The session ended with the load
+        if let Some(key) = auth_key.as_ref() {
+            client
+                .authenticate(key)
+                .map_err(|e| format!("authenticating: {:?}", e))?;
+        }
+        match client.request(&Request::Boot(BootCommand::BootIfBootable)) {
+            Ok(Response::ConfirmBootCmd {
+                will_boot: true, ..
+            }) => println!("Booting!"),
+            other => return Err(format!("boot failed: {:?}", other)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Read an image, and its signature if it has one
+fn read_image(image: &Path, signed: Option<&Path>) -> Result<(Vec<u8>, Option<Vec<u8>>), String> {
+    let data = fs::read(image).map_err(|e| format!("reading {}: {}", image.display(), e))?;
+    let signature = match signed {
+        Some(path) => {
+            let sig = fs::read(path).map_err(|e| format!("reading {}: {}", path.display(), e))?;
+            if sig.len() != 64 {
+                return Err(format!("{} is not a signature", path.display()));
             }
+            Some(sig)
+        }
+        None => None,
+    };
+    Ok((data, signature))
+}
+
+fn sign(
+    image: PathBuf,
+    key: PathBuf,
+    output: PathBuf,
+    chunk_size: u32,
+    app_version: u32,
+) -> Result<(), String> {
+    let image = fs::read(&image).map_err(|e| format!("reading {}: {}", image.display(), e))?;
+    let secret = read_key(&key, "secret key")?;
+
+    // Sign exactly what the device will hash: the padded image
+    let padded = pad_image(&image, chunk_size);
+    let signature = sig::sign(&secret, &padded, app_version);
+    fs::write(&output, signature).map_err(|e| format!("writing {}: {}", output.display(), e))?;
+    println!("Signed {} bytes (padded to {})", image.len(), padded.len());
+    Ok(())
+}
+
+fn keygen(output: PathBuf, force: bool) -> Result<(), String> {
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).map_err(|e| format!("getting randomness: {}", e))?;
+    let public = sig::public_key(&secret);
+
+    let pub_path = output.with_extension("pub");
+    for path in [&output, &pub_path] {
+        if !force && path.exists() {
+            return Err(format!(
+                "{} already exists, use --force to replace it",
+                path.display()
+            ));
         }
     }
+    write_new(&output, &secret, true, force)?;
+    write_new(&pub_path, &public, false, force)?;
+
+    // Ready to paste into the bootloader's `Flash` impl
+    let bytes = public
+        .iter()
+        .map(|b| format!("0x{:02x}", b))
+        .collect::<Vec<_>>();
+    println!("const APP_PUBLIC_KEY: [u8; 32] = [{}];", bytes.join(", "));
+    Ok(())
+}
+
+/// Write `data` to a new file, failing if there already is one unless
+/// `force`. A `secret` file is only readable by its owner, from the moment
+/// it exists.
+fn write_new(path: &Path, data: &[u8], secret: bool, force: bool) -> Result<(), String> {
+    let err = |e: std::io::Error| format!("writing {}: {}", path.display(), e);
+    if force {
+        // Start afresh, rather than keep the old file's permissions
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(err(e)),
+            _ => {}
+        }
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(data))
+        .map_err(err)
+}
+
+/// Read a raw 32-byte key file
+fn read_key(path: &Path, what: &str) -> Result<[u8; 32], String> {
+    fs::read(path)
+        .map_err(|e| format!("reading {}: {}", path.display(), e))?
+        .try_into()
+        .map_err(|_| format!("{} is not a {}", path.display(), what))
 }